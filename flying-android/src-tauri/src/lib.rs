@@ -22,13 +22,26 @@ impl ConnectionMode {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct DiscoveredHost {
+    id: String,
     name: String,
     ip: String,
 }
 
+impl From<flying::mdns::DiscoveredService> for DiscoveredHost {
+    fn from(service: flying::mdns::DiscoveredService) -> Self {
+        DiscoveredHost {
+            ip: service.address(),
+            name: service.label(),
+            id: service.fullname,
+        }
+    }
+}
+
 #[tauri::command]
 fn generate_password() -> Result<String, String> {
-    Ok(flying::utils::generate_password())
+    flying::utils::generate_password(flying::utils::PasswordFormat::default())
+        .map(|password| password.expose().to_string())
+        .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
@@ -133,23 +146,76 @@ async fn pick_folder(app: tauri::AppHandle) -> Result<Option<(String, String)>,
     Ok(Some((file_path, file_name)))
 }
 
+/// Whether this app can send to or receive from `service`. Peers in other modes, such
+/// as `flying share` or `flying sync`, and incompatible versions are left out.
+fn is_usable(service: &flying::mdns::DiscoveredService) -> bool {
+    use flying::mdns::Role;
+    service.is_compatible(Role::Sender) || service.is_compatible(Role::Receiver)
+}
+
+/// Starts a short scan and returns at once. Peers arrive as `host-discovered`,
+/// `host-updated`, `host-address-lost` and `host-lost` events, and `discovery-complete`
+/// carries how many were found when the scan ends.
 #[tauri::command]
-async fn discover_hosts() -> Result<Vec<DiscoveredHost>, String> {
-    let services = tokio::task::spawn_blocking(|| {
-        flying::mdns::discover_services(3).map_err(|e| e.to_string())
-    })
-    .await
-    .map_err(|e| format!("Task join error: {}", e))??;
-
-    let discovered: Vec<DiscoveredHost> = services
-        .into_iter()
-        .map(|service| DiscoveredHost {
-            name: service.hostname,
-            ip: service.ip.to_string(),
-        })
-        .collect();
-
-    Ok(discovered)
+async fn discover_hosts(window: tauri::Window) -> Result<(), String> {
+    use flying::mdns::{Discovery, DiscoveryEvent, DiscoveryMethod};
+
+    let mut discovery = Discovery::start(None, DiscoveryMethod::Both).map_err(|e| e.to_string())?;
+
+    tokio::spawn(async move {
+        let mut hosts: Vec<DiscoveredHost> = Vec::new();
+        let deadline = tokio::time::sleep(std::time::Duration::from_secs(3));
+        tokio::pin!(deadline);
+
+        loop {
+            let event = tokio::select! {
+                _ = &mut deadline => break,
+                event = discovery.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+            };
+
+            match event {
+                DiscoveryEvent::Appeared(service) | DiscoveryEvent::Updated(service) => {
+                    let usable = is_usable(&service);
+                    let host = DiscoveredHost::from(service);
+                    // One peer can be reachable at several addresses, each its own entry
+                    let known = hosts
+                        .iter()
+                        .position(|h| h.id == host.id && h.ip == host.ip);
+                    match (known, usable) {
+                        (None, true) => {
+                            let _ = window.emit("host-discovered", &host);
+                            hosts.push(host);
+                        }
+                        (Some(index), true) => {
+                            let _ = window.emit("host-updated", &host);
+                            hosts[index] = host;
+                        }
+                        (Some(index), false) => {
+                            let _ = window.emit("host-address-lost", &host);
+                            hosts.remove(index);
+                        }
+                        (None, false) => {}
+                    }
+                }
+                DiscoveryEvent::Lost(service) => {
+                    let host = DiscoveredHost::from(service);
+                    let _ = window.emit("host-address-lost", &host);
+                    hosts.retain(|h| h.id != host.id || h.ip != host.ip);
+                }
+                DiscoveryEvent::Gone(id) => {
+                    let _ = window.emit("host-lost", &id);
+                    hosts.retain(|h| h.id != id);
+                }
+            }
+        }
+
+        let _ = window.emit("discovery-complete", hosts.len());
+    });
+
+    Ok(())
 }
 
 #[tauri::command]
@@ -162,6 +228,7 @@ async fn send_file_from_uri(
     window: tauri::Window,
     app: tauri::AppHandle,
 ) -> Result<(), String> {
+    let password = flying::security::SecretString::new(password);
     let mode = connection_mode.to_flying_mode(connect_ip);
 
     std::thread::spawn(move || {
@@ -188,9 +255,16 @@ async fn send_file_from_uri(
                     .open_file_readable(&uri)
                     .await
                     .map_err(|e| format!("Failed to open file: {}", e))?;
-                flying::run_sender_from_handle(source_file, &file_name, &password, mode)
-                    .await
-                    .map_err(|e| format!("Send error: {}", e))?;
+                flying::run_sender_from_handle(
+                    source_file,
+                    &file_name,
+                    &password,
+                    mode,
+                    &flying::net::NetworkOptions::default(),
+                    &flying::security::SecurityOptions::default(),
+                )
+                .await
+                .map_err(|e| format!("Send error: {}", e))?;
                 Ok(())
             }
             .await;
@@ -198,9 +272,16 @@ async fn send_file_from_uri(
             #[cfg(not(target_os = "android"))]
             let result: Result<(), String> = async {
                 let file_path = std::path::PathBuf::from(&file_uri);
-                flying::run_sender(&file_path, &password, mode, false)
-                    .await
-                    .map_err(|e| format!("Send error: {}", e))?;
+                flying::run_sender(
+                    &file_path,
+                    &password,
+                    mode,
+                    false,
+                    &flying::net::NetworkOptions::default(),
+                    &flying::security::SecurityOptions::default(),
+                )
+                .await
+                .map_err(|e| format!("Send error: {}", e))?;
                 Ok(())
             }
             .await;
@@ -228,6 +309,7 @@ async fn send_file_from_uri(
     connect_ip: Option<String>,
     window: tauri::Window,
 ) -> Result<(), String> {
+    let password = flying::security::SecretString::new(password);
     let mode = connection_mode.to_flying_mode(connect_ip);
 
     std::thread::spawn(move || {
@@ -254,9 +336,16 @@ async fn send_file_from_uri(
                     .open_file_readable(&uri)
                     .await
                     .map_err(|e| format!("Failed to open file: {}", e))?;
-                flying::run_sender_from_handle(source_file, &file_name, &password, mode)
-                    .await
-                    .map_err(|e| format!("Send error: {}", e))?;
+                flying::run_sender_from_handle(
+                    source_file,
+                    &file_name,
+                    &password,
+                    mode,
+                    &flying::net::NetworkOptions::default(),
+                    &flying::security::SecurityOptions::default(),
+                )
+                .await
+                .map_err(|e| format!("Send error: {}", e))?;
                 Ok(())
             }
             .await;
@@ -264,9 +353,16 @@ async fn send_file_from_uri(
             #[cfg(not(target_os = "android"))]
             let result: Result<(), String> = async {
                 let file_path = std::path::PathBuf::from(&file_uri);
-                flying::run_sender(&file_path, &password, mode, false)
-                    .await
-                    .map_err(|e| format!("Send error: {}", e))?;
+                flying::run_sender(
+                    &file_path,
+                    &password,
+                    mode,
+                    false,
+                    &flying::net::NetworkOptions::default(),
+                    &flying::security::SecurityOptions::default(),
+                )
+                .await
+                .map_err(|e| format!("Send error: {}", e))?;
                 Ok(())
             }
             .await;
@@ -295,6 +391,7 @@ async fn receive_file(
     app: tauri::AppHandle,
     window: tauri::Window,
 ) -> Result<(), String> {
+    let password = flying::security::SecretString::new(password);
     let mode = connection_mode.to_flying_mode(connect_ip);
 
     tokio::spawn(async move {
@@ -313,9 +410,15 @@ async fn receive_file(
             // This is a temporary solution that uses the default path
             // TODO: Implement proper Android content URI writing in flying crate
             let download_dir = PathBuf::from("/storage/emulated/0/Download");
-            flying::run_receiver(&download_dir, &password, mode)
-                .await
-                .map_err(|e| format!("Receive error: {}", e))?;
+            flying::run_receiver(
+                &download_dir,
+                &password,
+                mode,
+                &flying::net::NetworkOptions::default(),
+                &flying::security::SecurityOptions::default(),
+            )
+            .await
+            .map_err(|e| format!("Receive error: {}", e))?;
 
             Ok(())
         }
@@ -343,13 +446,21 @@ async fn receive_file(
     output_dir_uri: String,
     window: tauri::Window,
 ) -> Result<(), String> {
+    let password = flying::security::SecretString::new(password);
     let output_dir = PathBuf::from(output_dir_uri);
     let mode = connection_mode.to_flying_mode(connect_ip);
 
     tokio::spawn(async move {
         let _ = window.emit("receive-start", serde_json::json!({}));
 
-        let result = flying::run_receiver(&output_dir, &password, mode).await;
+        let result = flying::run_receiver(
+            &output_dir,
+            &password,
+            mode,
+            &flying::net::NetworkOptions::default(),
+            &flying::security::SecurityOptions::default(),
+        )
+        .await;
 
         match result {
             Ok(_) => {
@@ -16,9 +16,11 @@ import {
   ContentCopy as CopyIcon,
 } from "@mui/icons-material";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 import { writeText } from "@tauri-apps/plugin-clipboard-manager";
 
 interface DiscoveredHost {
+  id: string;
   name: string;
   ip: string;
 }
@@ -32,23 +34,53 @@ function DiscoverPage() {
     setIsDiscovering(true);
     setHosts([]);
 
-    try {
-      const discovered = await invoke<DiscoveredHost[]>("discover_hosts");
-      setHosts(discovered);
-
-      if (discovered.length === 0) {
-        setSnackbar({ open: true, message: "No hosts found" });
-      } else {
+    const unlisteners = await Promise.all([
+      listen<DiscoveredHost>("host-discovered", (event) => {
+        setHosts((current) => [...current, event.payload]);
+      }),
+      listen<DiscoveredHost>("host-updated", (event) => {
+        setHosts((current) =>
+          current.map((host) =>
+            host.id === event.payload.id && host.ip === event.payload.ip
+              ? event.payload
+              : host,
+          ),
+        );
+      }),
+      listen<DiscoveredHost>("host-address-lost", (event) => {
+        setHosts((current) =>
+          current.filter(
+            (host) =>
+              host.id !== event.payload.id || host.ip !== event.payload.ip,
+          ),
+        );
+      }),
+      listen<string>("host-lost", (event) => {
+        setHosts((current) =>
+          current.filter((host) => host.id !== event.payload),
+        );
+      }),
+      listen<number>("discovery-complete", (event) => {
+        const found = event.payload;
         setSnackbar({
           open: true,
-          message: `Found ${discovered.length} host(s)`,
+          message: found === 0 ? "No hosts found" : `Found ${found} host(s)`,
         });
-      }
+        finish();
+      }),
+    ]);
+    const finish = () => {
+      unlisteners.forEach((unlisten) => unlisten());
+      setIsDiscovering(false);
+    };
+
+    try {
+      // Returns as soon as the scan starts; the events above fill in the list
+      await invoke("discover_hosts");
     } catch (error) {
       console.error("Failed to discover hosts:", error);
       setSnackbar({ open: true, message: `Discovery failed: ${error}` });
-    } finally {
-      setIsDiscovering(false);
+      finish();
     }
   };
 
@@ -0,0 +1,298 @@
+use crate::{
+    mdns::{Backend, BackendEvent, DiscoveredService},
+    net::{self, InterfaceSelector},
+};
+use if_addrs::IfAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+/// UDP port that listeners answer broadcast queries on.
+pub const DISCOVERY_PORT: u16 = crate::DEFAULT_PORT;
+
+const QUERY: &[u8] = b"FLYING-QUERY 1";
+const ANNOUNCE_HEADER: &str = "FLYING-ANNOUNCE 1";
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const PEER_EXPIRY: Duration = Duration::from_secs(5);
+const MAX_DATAGRAM: usize = 2048;
+/// Pause after a failed receive, so a socket that keeps failing cannot spin the loop.
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(200);
+/// Failed receives in a row after which a responder stops answering.
+const MAX_RECV_ERRORS: u32 = 50;
+
+fn udp_socket(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port).into())?;
+
+    let std_socket: std::net::UdpSocket = socket.into();
+    std_socket.set_nonblocking(true)?;
+    UdpSocket::from_std(std_socket)
+}
+
+fn encode_announce(
+    fullname: &str,
+    hostname: &str,
+    port: u16,
+    properties: &[(&'static str, String)],
+) -> Vec<u8> {
+    let mut message = format!(
+        "{}\ninstance={}\nhost={}\nport={}\n",
+        ANNOUNCE_HEADER, fullname, hostname, port
+    );
+    for (key, value) in properties {
+        message.push_str(&format!("{}={}\n", key, value.replace('\n', " ")));
+    }
+    message.into_bytes()
+}
+
+fn decode_announce(datagram: &[u8], ip: IpAddr) -> Option<DiscoveredService> {
+    let text = std::str::from_utf8(datagram).ok()?;
+    let mut lines = text.lines();
+    if lines.next()? != ANNOUNCE_HEADER {
+        return None;
+    }
+
+    let fields: HashMap<&str, &str> = lines.filter_map(|line| line.split_once('=')).collect();
+    let fullname = fields.get("instance")?;
+    let hostname = fields.get("host")?;
+    let port = fields.get("port")?.parse().ok()?;
+
+    Some(crate::mdns::service_from_properties(
+        fullname,
+        hostname,
+        ip,
+        0,
+        port,
+        |key| fields.get(key).copied(),
+    ))
+}
+
+/// Answers broadcast queries for as long as it is alive.
+pub struct Responder {
+    properties: watch::Sender<Vec<(&'static str, String)>>,
+    task: JoinHandle<()>,
+}
+
+impl Responder {
+    pub fn start(
+        port: u16,
+        interfaces: &[InterfaceSelector],
+        fullname: String,
+        hostname: String,
+        properties: Vec<(&'static str, String)>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let socket = udp_socket(DISCOVERY_PORT)?;
+        let local_interfaces = if interfaces.is_empty() {
+            None
+        } else {
+            Some(net::resolve_interfaces(interfaces)?)
+        };
+
+        let (properties, mut current) = watch::channel(properties);
+        let task = tokio::spawn(async move {
+            let mut buffer = [0u8; MAX_DATAGRAM];
+            let mut errors = 0;
+            loop {
+                let (len, from) = match socket.recv_from(&mut buffer).await {
+                    Ok(received) => {
+                        errors = 0;
+                        received
+                    }
+                    Err(e) => {
+                        errors += 1;
+                        if errors >= MAX_RECV_ERRORS {
+                            eprintln!("Stopped answering broadcast discovery: {}", e);
+                            return;
+                        }
+                        tokio::time::sleep(RECV_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+                if &buffer[..len] != QUERY {
+                    continue;
+                }
+
+                // With --bind, only answer peers on the chosen interfaces
+                if let Some(ref local_interfaces) = local_interfaces
+                    && !net::is_reachable_via(from.ip(), 0, local_interfaces)
+                {
+                    continue;
+                }
+
+                let announce =
+                    encode_announce(&fullname, &hostname, port, &current.borrow_and_update());
+                let _ = socket.send_to(&announce, from).await;
+            }
+        });
+
+        Ok(Responder { properties, task })
+    }
+
+    pub fn update(&self, properties: Vec<(&'static str, String)>) {
+        let _ = self.properties.send(properties);
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn broadcast_targets(
+    port: u16,
+    local_interfaces: Option<&[if_addrs::Interface]>,
+) -> Vec<SocketAddr> {
+    let interfaces = match local_interfaces {
+        Some(interfaces) => interfaces.to_vec(),
+        None => if_addrs::get_if_addrs().unwrap_or_default(),
+    };
+
+    let mut targets: Vec<SocketAddr> = interfaces
+        .iter()
+        .filter(|interface| !interface.is_loopback())
+        .filter_map(|interface| match &interface.addr {
+            IfAddr::V4(v4) => v4.broadcast,
+            IfAddr::V6(_) => None,
+        })
+        .map(|broadcast| SocketAddr::new(IpAddr::V4(broadcast), port))
+        .collect();
+
+    if local_interfaces.is_none() {
+        targets.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port));
+    }
+    targets.dedup();
+    targets
+}
+
+/// Queries for listeners on the local broadcast domains until `tx` is closed.
+pub(crate) async fn browse(
+    local_interfaces: Option<Vec<if_addrs::Interface>>,
+    tx: mpsc::Sender<BackendEvent>,
+) {
+    let Ok(socket) = udp_socket(0) else {
+        return;
+    };
+
+    let targets = broadcast_targets(DISCOVERY_PORT, local_interfaces.as_deref());
+    let mut peers: HashMap<String, (Vec<DiscoveredService>, Instant)> = HashMap::new();
+    let mut interval = tokio::time::interval(QUERY_INTERVAL);
+    let mut buffer = [0u8; MAX_DATAGRAM];
+
+    loop {
+        let mut events = Vec::new();
+
+        tokio::select! {
+            _ = tx.closed() => break,
+            _ = interval.tick() => {
+                for target in &targets {
+                    let _ = socket.send_to(QUERY, target).await;
+                }
+
+                // Broadcast has no goodbye, so peers that stop answering are gone
+                let expired: Vec<String> = peers
+                    .iter()
+                    .filter(|(_, (_, last_seen))| last_seen.elapsed() > PEER_EXPIRY)
+                    .map(|(fullname, _)| fullname.clone())
+                    .collect();
+                for fullname in expired {
+                    peers.remove(&fullname);
+                    events.push(BackendEvent::Removed(Backend::Broadcast, fullname));
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let Ok((len, from)) = received else {
+                    tokio::time::sleep(RECV_ERROR_BACKOFF).await;
+                    continue;
+                };
+                let Some(service) = decode_announce(&buffer[..len], from.ip()) else {
+                    continue;
+                };
+                if from.ip().is_loopback() {
+                    continue;
+                }
+                if let Some(ref local_interfaces) = local_interfaces
+                    && !net::is_reachable_via(service.ip, 0, local_interfaces)
+                {
+                    continue;
+                }
+
+                let fullname = service.fullname.clone();
+                let (services, last_seen) = peers
+                    .entry(fullname.clone())
+                    .or_insert_with(|| (Vec::new(), Instant::now()));
+                *last_seen = Instant::now();
+                services.retain(|s| s.ip != service.ip);
+                services.push(service);
+                events.push(BackendEvent::Resolved(
+                    Backend::Broadcast,
+                    fullname,
+                    services.clone(),
+                ));
+            }
+        }
+
+        for event in events {
+            if tx.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mdns::Role;
+
+    #[test]
+    fn announcements_decode_what_was_encoded() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        let datagram = encode_announce(
+            "laptop._flying._tcp.local.",
+            "laptop.local.",
+            3290,
+            &[
+                ("version", "12".to_string()),
+                ("role", "send".to_string()),
+                ("name", "My\nLaptop".to_string()),
+                ("ciphers", "aes256gcm,chacha20poly1305".to_string()),
+                ("session", "427".to_string()),
+                ("status", "busy".to_string()),
+            ],
+        );
+
+        let service = decode_announce(&datagram, ip).unwrap();
+        assert_eq!(service.fullname, "laptop._flying._tcp.local.");
+        assert_eq!(service.hostname, "laptop.local.");
+        assert_eq!((service.ip, service.scope_id, service.port), (ip, 0, 3290));
+        assert_eq!(service.version, Some(12));
+        assert_eq!(service.role, Some(Role::Sender));
+        assert_eq!(service.device_name, "My Laptop");
+        assert_eq!(service.ciphers, ["aes256gcm", "chacha20poly1305"]);
+        assert_eq!(service.session.as_deref(), Some("427"));
+        assert!(service.busy);
+    }
+
+    #[test]
+    fn foreign_datagrams_are_ignored() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert!(decode_announce(QUERY, ip).is_none());
+        assert!(decode_announce(b"\xff\xfe", ip).is_none());
+        assert!(decode_announce(b"FLYING-ANNOUNCE 2\ninstance=a\nhost=b\nport=1\n", ip).is_none());
+        assert!(decode_announce(b"FLYING-ANNOUNCE 1\ninstance=a\nhost=b\n", ip).is_none());
+        assert!(
+            decode_announce(b"FLYING-ANNOUNCE 1\ninstance=a\nhost=b\nport=99999\n", ip).is_none()
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Sends one set of files to several receivers at once, reading each file only once.
+
+use crate::{
+    FileSet, mdns,
+    net::{self, TimeoutStream},
+    protocol::{self, Reason, ReasonCode},
+    security::{self, SecretString},
+    send, utils,
+};
+use ring::rand;
+use std::{
+    fs::File,
+    io::Read,
+    net::SocketAddr,
+    path::PathBuf,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+
+/// Chunks queued for each receiver before the slowest one holds back the others.
+const QUEUE_CHUNKS: usize = 8;
+
+/// What the reading side hands every receiver's session.
+#[derive(Clone)]
+enum Event {
+    File {
+        index: u64,
+        path: Arc<PathBuf>,
+        name: Arc<str>,
+        size: u64,
+    },
+    Chunk {
+        offset: u64,
+        data: Arc<[u8]>,
+    },
+    FileEnd,
+}
+
+/// The reading side's handle on one receiver's session.
+struct Queue {
+    addr: SocketAddr,
+    events: mpsc::Sender<Event>,
+    /// Set when the transfer stops on this side. Kept apart from `events`, which may
+    /// be full, so the reason always reaches the session.
+    abort: watch::Sender<Option<Reason>>,
+}
+
+/// What every session needs, shared between them.
+struct Settings {
+    manifest: protocol::Manifest,
+    network: net::NetworkOptions,
+    security: security::SecurityOptions,
+}
+
+/// One receiver's progress through a file, printed in quarters so that lines from
+/// different receivers don't overwrite each other.
+struct Progress {
+    name: Arc<str>,
+    start: u64,
+    /// Where the data sent so far ends.
+    sent: u64,
+    size: u64,
+    last_quarter: u64,
+}
+
+impl Progress {
+    fn update(&mut self, addr: SocketAddr, bytes_sent: u64) {
+        self.sent = bytes_sent;
+        let quarter = bytes_sent * 4 / self.size.max(1);
+        if quarter > self.last_quarter && quarter < 4 {
+            status!("{}: {} {}%", addr, self.name, quarter * 25);
+            self.last_quarter = quarter;
+        }
+    }
+}
+
+/// Resolves with the reason once the transfer is aborted.
+async fn aborted(abort: &mut watch::Receiver<Option<Reason>>) -> Reason {
+    let reason = abort
+        .wait_for(Option::is_some)
+        .await
+        .ok()
+        .and_then(|reason| reason.clone());
+    match reason {
+        Some(reason) => reason,
+        None => std::future::pending().await,
+    }
+}
+
+/// The receiving end of a `Queue`.
+struct Events {
+    events: mpsc::Receiver<Event>,
+    abort: watch::Receiver<Option<Reason>>,
+}
+
+/// Forwards the shared file stream to one receiver, leaving out what it already has.
+async fn forward(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    Events { events, abort }: &mut Events,
+    first_file: u64,
+    addr: SocketAddr,
+    files: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rng = rand::SystemRandom::new();
+    // `None` while the receiver skips the current file
+    let mut current: Option<Progress> = None;
+    let mut ended = 0;
+
+    loop {
+        let next = async {
+            tokio::select! {
+                biased;
+                reason = aborted(abort) => Err(reason),
+                event = events.recv() => Ok(event),
+            }
+        };
+        // Waiting on slower receivers or the disk is not idleness. The abort is checked
+        // inside, so it never cuts a heartbeat off halfway through the frame.
+        let event = utils::with_heartbeat(stream, next).await??;
+        let Some(event) = event else {
+            if ended < files {
+                return Err("The sender stopped before the transfer was complete".into());
+            }
+            return Ok(());
+        };
+        match event {
+            Event::File {
+                index,
+                path,
+                name,
+                size,
+            } => {
+                current = None;
+                if index < first_file {
+                    continue;
+                }
+                let file = File::open(&*path)
+                    .map_err(|e| Reason::io(e, format_args!("reading {}", name)))?;
+                match send::negotiate_start(stream, &file, &name, size, key).await? {
+                    None => status!("{}: already has {}, skipping", addr, name),
+                    Some(offset) if offset > size => {
+                        return Err("Invalid resume offset from receiver".into());
+                    }
+                    Some(offset) => {
+                        current = Some(Progress {
+                            name,
+                            start: offset,
+                            sent: offset,
+                            size,
+                            last_quarter: 0,
+                        });
+                    }
+                }
+            }
+            Event::Chunk { offset, data } => {
+                let Some(progress) = current.as_mut() else {
+                    continue;
+                };
+                let end = offset + data.len() as u64;
+                if end <= progress.start {
+                    continue;
+                }
+                let from = progress.start.saturating_sub(offset) as usize;
+                send::write_chunk(stream, key, &rng, &data[from..]).await?;
+                progress.update(addr, end);
+            }
+            Event::FileEnd => {
+                ended += 1;
+                if let Some(progress) = current.take() {
+                    send::write_end(stream, key, &rng, progress.sent).await?;
+                    status!("{}: {} done", addr, progress.name);
+                }
+            }
+        }
+    }
+}
+
+/// Authenticates one receiver, reports it ready, then forwards the file stream to it.
+async fn session(
+    mut stream: TimeoutStream,
+    addr: SocketAddr,
+    password: SecretString,
+    settings: Rc<Settings>,
+    mut events: Events,
+    ready: mpsc::UnboundedSender<SocketAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Settings {
+        manifest,
+        network,
+        security,
+    } = &*settings;
+    let (mut key, sas) = crate::with_handshake_timeout(
+        network,
+        utils::send_handshake(&mut stream, &password, security, manifest.clone()),
+    )
+    .await?;
+    status!("Receiver {} authenticated", addr);
+
+    let result = async {
+        security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
+        let _ = ready.send(addr);
+        let first_file = crate::receive_resume_position(&mut stream, &key).await?;
+        forward(
+            &mut stream,
+            &mut key,
+            &mut events,
+            first_file,
+            addr,
+            manifest.files,
+        )
+        .await
+    }
+    .await;
+    match &result {
+        Ok(()) => stream.shutdown().await?,
+        Err(e) => protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await,
+    }
+    result
+}
+
+/// Hands `event` to every session still running, dropping the ones that ended.
+async fn send_all(queues: &mut Vec<Queue>, event: Event) {
+    // Queues stay in place while waiting, so an interrupted send can still abort them all
+    let mut closed = Vec::new();
+    for (i, queue) in queues.iter().enumerate() {
+        if queue.events.send(event.clone()).await.is_err() {
+            closed.push(i);
+        }
+    }
+    for i in closed.into_iter().rev() {
+        queues.remove(i);
+    }
+}
+
+/// Reads every file once and queues its chunks for all receivers.
+async fn fan_out(
+    file_set: &FileSet,
+    queues: &mut Vec<Queue>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (index, path) in file_set.files.iter().enumerate() {
+        if queues.is_empty() {
+            break;
+        }
+        let name: Arc<str> = send::relative_name(path, &file_set.base_path).into();
+        let describe = |e| Reason::io(e, format_args!("reading {}", name));
+        let size = path.metadata().map_err(describe)?.len();
+        let mut file = File::open(path).map_err(describe)?;
+
+        let event = Event::File {
+            index: index as u64,
+            path: Arc::new(path.clone()),
+            name: name.clone(),
+            size,
+        };
+        send_all(queues, event).await;
+
+        let mut offset = 0;
+        loop {
+            let read_task = tokio::task::spawn_blocking(move || {
+                let mut buffer = vec![0u8; send::CHUNK_SIZE];
+                let result = file.read(&mut buffer).map(|n| {
+                    buffer.truncate(n);
+                    buffer
+                });
+                (file, result)
+            });
+            let (returned_file, result) = read_task.await?;
+            file = returned_file;
+
+            let data = result.map_err(describe)?;
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len() as u64;
+            send_all(
+                queues,
+                Event::Chunk {
+                    offset,
+                    data: data.into(),
+                },
+            )
+            .await;
+            offset += len;
+        }
+        send_all(queues, Event::FileEnd).await;
+    }
+    Ok(())
+}
+
+fn abort_all(queues: &[Queue], error: &(dyn std::error::Error + 'static)) {
+    let reason = error
+        .downcast_ref::<Reason>()
+        .cloned()
+        .unwrap_or_else(|| Reason::new(ReasonCode::Other, error.to_string()));
+    for queue in queues {
+        queue.abort.send_replace(Some(reason.clone()));
+    }
+}
+
+/// Accepts receivers until `receivers` are ready or `start_after` has passed, then
+/// sends `file_set` to all of them. Must run inside a `LocalSet`.
+pub(crate) async fn serve(
+    file_set: FileSet,
+    password: &SecretString,
+    receivers: usize,
+    start_after: Option<Duration>,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut password = password.clone();
+    let listener = net::create_listener(crate::DEFAULT_PORT, &network.bind)?;
+    let metadata = crate::service_metadata(mdns::Role::Sender, &password, network, security);
+    let mut advertisement = mdns::Advertisement::start(
+        crate::DEFAULT_PORT,
+        &network.bind,
+        metadata,
+        network.discovery,
+    )?;
+    let mut guard = security::AccessGuard::new(security);
+
+    let settings = Rc::new(Settings {
+        manifest: file_set.manifest.clone(),
+        network: network.clone(),
+        security: security.clone(),
+    });
+    let (ready_tx, mut ready_rx) = mpsc::unbounded_channel();
+    let mut sessions = JoinSet::new();
+    let mut queues: Vec<Queue> = Vec::new();
+    let mut ready: Vec<SocketAddr> = Vec::new();
+
+    status!("Listening on {}...", listener.describe());
+    status!("Waiting for {} receiver(s) to connect...\n", receivers);
+    let deadline = async {
+        match start_after {
+            Some(duration) => tokio::time::sleep(duration).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    let gathered: Result<(), Box<dyn std::error::Error>> = loop {
+        tokio::select! {
+            accepted = listener.accept(network) => match accepted {
+                Ok((_, addr)) if !guard.admit(addr) => {}
+                Ok((stream, addr)) => {
+                    // Shown in every line about this receiver
+                    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                    status!("Connection accepted from {}", addr);
+                    let (events_tx, events) = mpsc::channel(QUEUE_CHUNKS);
+                    let (abort_tx, abort) = watch::channel(None);
+                    queues.push(Queue {
+                        addr,
+                        events: events_tx,
+                        abort: abort_tx,
+                    });
+                    let session = session(
+                        stream,
+                        addr,
+                        password.clone(),
+                        settings.clone(),
+                        Events { events, abort },
+                        ready_tx.clone(),
+                    );
+                    sessions.spawn_local(async move { (addr, session.await) });
+                }
+                // Whoever is ready by the accept timeout still gets the files
+                Err(e) if ready.is_empty() => break Err(e),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break Ok(());
+                }
+            },
+            Some(addr) = ready_rx.recv() => {
+                guard.record_success(addr);
+                ready.push(addr);
+                status!("Receiver {} is ready ({} of {})", addr, ready.len(), receivers);
+                if ready.len() >= receivers {
+                    break Ok(());
+                }
+            }
+            Some(joined) = sessions.join_next() => {
+                let (addr, result) = joined?;
+                queues.retain(|queue| queue.addr != addr);
+                ready.retain(|ready| *ready != addr);
+                if let Err(e) = result {
+                    eprintln!("Receiver {}: {}", addr, e);
+                    if let Some(auth_error) = e.downcast_ref()
+                        && let Err(e) = crate::record_auth_failure(
+                            &mut guard,
+                            &mut advertisement,
+                            &mut password,
+                            security,
+                            addr,
+                            auth_error,
+                        )
+                    {
+                        break Err(e);
+                    }
+                }
+            }
+            _ = &mut deadline, if !ready.is_empty() => break Ok(()),
+            _ = tokio::signal::ctrl_c() => {
+                let reason = Reason::new(ReasonCode::Interrupted, "Interrupted by the user");
+                break Err(reason.into());
+            }
+        }
+    };
+    drop(listener);
+    drop(advertisement);
+
+    // Receivers still authenticating missed the start
+    let (started, late): (Vec<Queue>, Vec<Queue>) = queues
+        .into_iter()
+        .partition(|queue| ready.contains(&queue.addr));
+    let too_late = Reason::new(
+        ReasonCode::Rejected,
+        "The transfer started without this receiver",
+    );
+    abort_all(&late, &too_late);
+    let mut queues = started;
+
+    let start = Instant::now();
+    let result = match gathered {
+        Ok(()) => {
+            status!(
+                "Sending {} file(s) to {} receiver(s)...\n",
+                file_set.files.len(),
+                queues.len()
+            );
+            tokio::select! {
+                result = fan_out(&file_set, &mut queues) => result,
+                _ = tokio::signal::ctrl_c() => {
+                    let reason = Reason::new(ReasonCode::Interrupted, "Interrupted by the user");
+                    Err(reason.into())
+                }
+            }
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &result {
+        abort_all(&queues, e.as_ref());
+    }
+    drop(queues);
+
+    // Every session ends once its queue is closed
+    let mut failed = 0;
+    while let Some(joined) = sessions.join_next().await {
+        let (addr, session_result) = joined?;
+        let counted = ready.contains(&addr);
+        match session_result {
+            Ok(()) => status!("{}: transfer complete", addr),
+            Err(e) if counted && result.is_ok() => {
+                eprintln!("{}: {}", addr, e);
+                failed += 1;
+            }
+            Err(_) => {}
+        }
+    }
+    result?;
+    status!(
+        "\nSending took {}",
+        humantime::format_duration(Duration::from_secs(start.elapsed().as_secs()))
+    );
+
+    status!("\n===========================================");
+    if failed > 0 {
+        status!("Transfer finished with errors");
+        status!("===========================================");
+        return Err(format!("{} of {} receiver(s) failed", failed, ready.len()).into());
+    }
+    status!("Transfer complete!");
+    status!("===========================================");
+    Ok(())
+}
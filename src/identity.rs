@@ -0,0 +1,266 @@
+use ring::{
+    digest, rand,
+    signature::{self, Ed25519KeyPair, KeyPair},
+};
+use std::{fmt, fs, io::Write, path::PathBuf, sync::Arc};
+use zeroize::Zeroizing;
+
+const IDENTITY_FILE: &str = "identity.pk8";
+const TRUST_STORE_FILE: &str = "trusted";
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+fn config_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let mut dir = dirs::config_dir().ok_or("Cannot find a configuration directory")?;
+    dir.push("flying");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Short, human-comparable digest of a public key, e.g. `3f2a-91c0-5b7e-d412`.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, public_key);
+    hash.as_ref()[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// This device's long-term Ed25519 key, created on first use.
+pub struct Identity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    pub fn load_or_create() -> Result<Self, Box<dyn std::error::Error>> {
+        let path = config_dir()?.join(IDENTITY_FILE);
+
+        if path.exists() {
+            let pkcs8 = Zeroizing::new(fs::read(&path)?);
+            let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+                .map_err(|_| format!("Corrupt identity key in {}", path.display()))?;
+            return Ok(Identity { key_pair });
+        }
+
+        // ring's document cannot be wiped, so it is copied at once and only the copy is used
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rand::SystemRandom::new())
+            .map(|document| Zeroizing::new(document.as_ref().to_vec()))
+            .map_err(|_| "Failed to generate identity key")?;
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(&path)?.write_all(&pkcs8)?;
+
+        let key_pair =
+            Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| "Failed to load identity key")?;
+        Ok(Identity { key_pair })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.public_key())
+    }
+
+    pub(crate) fn sign(&self, message: &[u8]) -> signature::Signature {
+        self.key_pair.sign(message)
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("fingerprint", &self.fingerprint())
+            .finish()
+    }
+}
+
+/// A peer whose identity key was pinned by `flying pair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedDevice {
+    pub name: String,
+    pub public_key: Vec<u8>,
+}
+
+impl TrustedDevice {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    fn matches(&self, name_or_fingerprint: &str) -> bool {
+        self.name == name_or_fingerprint || self.fingerprint() == name_or_fingerprint
+    }
+}
+
+/// Paired devices, stored one per line as `<public key hex> <name>`.
+pub struct TrustStore {
+    path: PathBuf,
+    devices: Vec<TrustedDevice>,
+}
+
+impl TrustStore {
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::load_from(config_dir()?.join(TRUST_STORE_FILE))
+    }
+
+    fn load_from(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut devices = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, name) = line.split_once(' ').unwrap_or((line, ""));
+            let public_key = from_hex(key)
+                .filter(|key| key.len() == PUBLIC_KEY_LEN)
+                .ok_or_else(|| format!("Invalid entry in {}: {}", path.display(), line))?;
+            devices.push(TrustedDevice {
+                name: name.to_string(),
+                public_key,
+            });
+        }
+
+        Ok(TrustStore { path, devices })
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let contents: String = self
+            .devices
+            .iter()
+            .map(|device| format!("{} {}\n", to_hex(&device.public_key), device.name))
+            .collect();
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+
+    pub fn devices(&self) -> &[TrustedDevice] {
+        &self.devices
+    }
+
+    pub fn find(&self, name_or_fingerprint: &str) -> Option<&TrustedDevice> {
+        self.devices.iter().find(|d| d.matches(name_or_fingerprint))
+    }
+
+    /// Pins `device`, replacing an earlier entry with the same key.
+    pub fn add(&mut self, device: TrustedDevice) -> Result<(), Box<dyn std::error::Error>> {
+        self.devices.retain(|d| d.public_key != device.public_key);
+        self.devices.push(device);
+        self.save()
+    }
+
+    pub fn remove(
+        &mut self,
+        name_or_fingerprint: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let before = self.devices.len();
+        self.devices.retain(|d| !d.matches(name_or_fingerprint));
+        if self.devices.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+/// Authenticates a session with identity keys instead of a password.
+#[derive(Debug, Clone)]
+pub struct TrustedSession {
+    pub identity: Arc<Identity>,
+    /// Devices accepted as the peer.
+    pub peers: Vec<TrustedDevice>,
+}
+
+impl TrustedSession {
+    /// Accepts every paired device, or only `peer` when given.
+    pub fn load(peer: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        let identity = Arc::new(Identity::load_or_create()?);
+        let store = TrustStore::load()?;
+
+        let peers = match peer {
+            Some(peer) => vec![
+                store
+                    .find(peer)
+                    .ok_or_else(|| format!("No paired device named '{}'", peer))?
+                    .clone(),
+            ],
+            None => store.devices().to_vec(),
+        };
+        if peers.is_empty() {
+            return Err("No paired devices, run `flying pair` first".into());
+        }
+
+        Ok(TrustedSession { identity, peers })
+    }
+
+    pub fn find_peer(&self, public_key: &[u8]) -> Option<&TrustedDevice> {
+        self.peers.iter().find(|d| d.public_key == public_key)
+    }
+
+    pub fn accepts_fingerprint(&self, fingerprint: &str) -> bool {
+        self.peers.iter().any(|d| d.fingerprint() == fingerprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, key: u8) -> TrustedDevice {
+        TrustedDevice {
+            name: name.to_string(),
+            public_key: vec![key; PUBLIC_KEY_LEN],
+        }
+    }
+
+    #[test]
+    fn paired_devices_persist() {
+        let path = std::env::temp_dir().join(format!("flying-trusted-{}", std::process::id()));
+        let mut store = TrustStore::load_from(path.clone()).unwrap();
+        assert!(store.devices().is_empty());
+
+        store.add(device("laptop", 1)).unwrap();
+        store.add(device("phone", 2)).unwrap();
+        // Pairing the same key again renames it instead of adding a second entry
+        store.add(device("old laptop", 1)).unwrap();
+
+        let mut store = TrustStore::load_from(path.clone()).unwrap();
+        assert_eq!(
+            store.devices(),
+            [device("phone", 2), device("old laptop", 1)]
+        );
+        assert_eq!(store.find("old laptop"), Some(&device("old laptop", 1)));
+        let fingerprint = device("phone", 2).fingerprint();
+        assert_eq!(store.find(&fingerprint), Some(&device("phone", 2)));
+        assert_eq!(store.find("laptop"), None);
+
+        assert!(store.remove(&fingerprint).unwrap());
+        assert!(!store.remove(&fingerprint).unwrap());
+        let store = TrustStore::load_from(path.clone()).unwrap();
+        assert_eq!(store.devices(), [device("old laptop", 1)]);
+
+        fs::write(&path, "abcd laptop\n").unwrap();
+        assert!(TrustStore::load_from(path.clone()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
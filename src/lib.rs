@@ -1,12 +1,40 @@
+/// Like `println!`, but writes to stderr once stdout carries received data.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::utils::stdout_is_data() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod broadcast;
+mod fanout;
+pub mod identity;
 pub mod mdns;
+pub mod net;
+pub mod protocol;
 mod receive;
+pub mod security;
 mod send;
+mod share;
+mod sync;
 pub mod utils;
 
-use std::net::SocketAddr;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use security::SecretString;
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
 
-pub const VERSION: u64 = 5;
+/// Newest protocol version this build speaks.
+pub const VERSION: u64 = 12;
+/// Oldest protocol version this build still speaks.
+pub const MIN_VERSION: u64 = 12;
 const DEFAULT_PORT: u16 = 3290;
 
 #[derive(Debug, Clone)]
@@ -30,28 +58,27 @@ impl ConnectionMode {
 
 fn select_service(services: &[mdns::DiscoveredService]) -> Option<&mdns::DiscoveredService> {
     if services.is_empty() {
-        println!("\nNo peers found on the network.");
-        println!("Make sure the peer is running and on the same network.");
+        status!("\nNo peers found on the network.");
+        status!("Make sure the peer is running and on the same network.");
         return None;
     }
 
-    println!("\nFound {} peer(s):", services.len());
+    status!("\nFound {} peer(s):", services.len());
     for (i, service) in services.iter().enumerate() {
-        println!(
-            "  [{}] {} ({}:{})",
+        status!(
+            "  [{}] {} ({})",
             i + 1,
-            service.hostname,
-            service.ip,
-            service.port
+            service.label(),
+            service.socket_addr()
         );
     }
 
     if services.len() == 1 {
-        println!("\nAutomatically selecting the only available receiver.");
+        status!("\nAutomatically selecting the only available receiver.");
         return Some(&services[0]);
     }
 
-    println!("\nSelect a peer (1-{}):", services.len());
+    status!("\nSelect a peer (1-{}):", services.len());
 
     let mut input = String::new();
     std::io::stdin().read_line(&mut input).ok()?;
@@ -61,100 +88,383 @@ fn select_service(services: &[mdns::DiscoveredService]) -> Option<&mdns::Discove
     if selection > 0 && selection <= services.len() {
         Some(&services[selection - 1])
     } else {
-        println!("Invalid selection.");
+        status!("Invalid selection.");
         None
     }
 }
 
+fn service_metadata(
+    role: mdns::Role,
+    password: &SecretString,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> mdns::ServiceMetadata {
+    let mut metadata = mdns::ServiceMetadata::new(
+        role,
+        network.device_name.as_deref(),
+        utils::session_tag(password.expose()),
+    );
+    if let Some(ref trusted) = security.trusted {
+        metadata.fingerprint = Some(trusted.identity.fingerprint());
+    }
+    metadata
+}
+
+async fn connect_when_found(
+    network: &net::NetworkOptions,
+    matches: impl FnMut(&mdns::DiscoveredService) -> bool,
+) -> Result<net::TimeoutStream, Box<dyn std::error::Error>> {
+    let (stream, service) =
+        connect_to_first_peer(network, Some(network.discovery_timeout), matches).await?;
+    status!(
+        "Connected to {} ({})!\n",
+        service.label(),
+        service.socket_addr()
+    );
+    Ok(stream)
+}
+
 async fn establish_connection(
     mode: &ConnectionMode,
-) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    role: mdns::Role,
+    password: &SecretString,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<net::TimeoutStream, Box<dyn std::error::Error>> {
     match mode {
         ConnectionMode::AutoDiscover => {
-            println!("Searching for peers on the local network...\n");
-            let services = mdns::discover_services(3)?;
+            // Paired devices and tagged passwords identify their listener, so connect
+            // as soon as it appears
+            if let Some(ref trusted) = security.trusted {
+                status!("Searching for a paired device...\n");
+                return connect_when_found(network, |service| {
+                    service
+                        .fingerprint
+                        .as_deref()
+                        .is_some_and(|fingerprint| trusted.accepts_fingerprint(fingerprint))
+                        && service.is_compatible(role)
+                })
+                .await;
+            }
+            if let Some(tag) = utils::session_tag(password.expose()) {
+                status!("Searching for the peer with session {}...\n", tag);
+                return connect_when_found(network, |service| {
+                    service.session.as_deref() == Some(tag) && service.is_compatible(role)
+                })
+                .await;
+            }
+
+            status!("Searching for peers on the local network...\n");
+            let mut services = mdns::discover_services(
+                Duration::from_secs(3),
+                network.source.as_ref(),
+                network.discovery,
+            )
+            .await?;
+            services.retain(|service| service.is_compatible(role));
 
             if let Some(service) = select_service(&services) {
-                let addr = SocketAddr::new(service.ip, service.port);
-                println!("\nConnecting to {}...", addr);
-                let stream = TcpStream::connect(addr).await?;
-                println!("Connected!\n");
+                let addr = service.socket_addr();
+                status!("\nConnecting to {}...", addr);
+                let stream = net::connect(addr, network).await?;
+                status!("Connected!\n");
                 Ok(stream)
             } else {
-                Err("No peers found on the local network".into())
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "No peers found on the local network",
+                )
+                .into())
             }
         }
         ConnectionMode::Listen => {
-            let listener = utils::create_listener(DEFAULT_PORT)?;
-            let _mdns = mdns::advertise_service(DEFAULT_PORT)?;
+            let listener = net::create_listener(DEFAULT_PORT, &network.bind)?;
+            let metadata = service_metadata(role, password, network, security);
+            let _advertisement = mdns::Advertisement::start(
+                DEFAULT_PORT,
+                &network.bind,
+                metadata,
+                network.discovery,
+            )?;
 
-            println!(
-                "Listening on [::]:{} (IPv4/IPv6 dual-stack)...",
-                DEFAULT_PORT
-            );
-            println!("Waiting for peer to connect...\n");
-            let (stream, socket_addr) = listener.accept().await?;
-            println!("Connection accepted from {}\n", socket_addr);
+            status!("Listening on {}...", listener.describe());
+            status!("Waiting for peer to connect...\n");
+            // Return on Ctrl+C so the advertisement is dropped and says goodbye
+            let mut guard = security::AccessGuard::new(security);
+            let (stream, socket_addr) = loop {
+                let (stream, socket_addr) = tokio::select! {
+                    accepted = listener.accept(network) => accepted?,
+                    _ = tokio::signal::ctrl_c() => return Err("Interrupted".into()),
+                };
+                if guard.admit(socket_addr) {
+                    break (stream, socket_addr);
+                }
+            };
+            status!("Connection accepted from {}\n", socket_addr);
             Ok(stream)
         }
         ConnectionMode::Connect(ip) => {
-            let ip: std::net::IpAddr = ip.parse()?;
-            let addr = std::net::SocketAddr::new(ip, DEFAULT_PORT);
-            println!("Connecting to {}...", addr);
-            let stream = TcpStream::connect(addr).await?;
-            println!("Connected!\n");
+            let (ip, scope_id) = net::parse_scoped_ip(ip)?;
+            let addr = net::scoped_socket_addr(ip, scope_id, DEFAULT_PORT);
+            status!("Connecting to {}...", addr);
+            let stream = net::connect(addr, network).await?;
+            status!("Connected!\n");
             Ok(stream)
         }
     }
 }
 
+/// Connects to the first discovered peer accepted by `matches`, as soon as it appears.
+///
+/// Peers that refuse the connection are skipped and the search continues.
+pub async fn connect_to_first_peer(
+    network: &net::NetworkOptions,
+    timeout: Option<Duration>,
+    mut matches: impl FnMut(&mdns::DiscoveredService) -> bool,
+) -> Result<(net::TimeoutStream, mdns::DiscoveredService), Box<dyn std::error::Error>> {
+    let mut discovery = mdns::Discovery::start(network.source.as_ref(), network.discovery)?;
+
+    let search = async {
+        while let Some(event) = discovery.next().await {
+            let (mdns::DiscoveryEvent::Appeared(service) | mdns::DiscoveryEvent::Updated(service)) =
+                event
+            else {
+                continue;
+            };
+            if !matches(&service) {
+                continue;
+            }
+
+            match net::connect(service.socket_addr(), network).await {
+                Ok(stream) => return Ok((stream, service)),
+                Err(e) => eprintln!("Could not connect to {}: {}", service.socket_addr(), e),
+            }
+        }
+        Err("Peer discovery stopped".into())
+    };
+
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, search).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "No matching peer appeared on the local network",
+            )
+        })?,
+        None => search.await,
+    }
+}
+
+async fn with_handshake_timeout<T>(
+    network: &net::NetworkOptions,
+    handshake: impl Future<Output = Result<T, Box<dyn std::error::Error>>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    tokio::time::timeout(network.handshake_timeout, handshake)
+        .await
+        .map_err(|_| net::TimeoutError::Handshake(network.handshake_timeout))?
+}
+
+/// Runs `transfer` until it finishes or the user presses Ctrl-C.
+async fn interruptible<T>(
+    transfer: impl Future<Output = Result<T, Box<dyn std::error::Error>>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    tokio::select! {
+        result = transfer => result,
+        _ = tokio::signal::ctrl_c() => {
+            let reason = protocol::Reason::new(
+                protocol::ReasonCode::Interrupted,
+                "Interrupted by the user",
+            );
+            Err(reason.into())
+        }
+    }
+}
+
+fn is_interrupted(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<protocol::Reason>()
+        .is_some_and(|reason| reason.code == protocol::ReasonCode::Interrupted)
+}
+
+/// Waits before the next attempt if `error` is a connection failure and retries remain.
+async fn retry_after(
+    error: &(dyn std::error::Error + 'static),
+    network: &net::NetworkOptions,
+    retries: &mut u32,
+) -> bool {
+    use std::io::ErrorKind;
+    // Local failures such as a full disk would only happen again
+    let dropped = error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof
+                | ErrorKind::TimedOut
+                | ErrorKind::HostUnreachable
+                | ErrorKind::NetworkUnreachable
+                | ErrorKind::NetworkDown
+        )
+    });
+    let retryable = dropped || error.is::<net::TimeoutError>();
+    if !retryable || *retries >= network.retries {
+        return false;
+    }
+
+    *retries += 1;
+    eprintln!(
+        "Reconnecting in {} (retry {} of {})...",
+        humantime::format_duration(network.retry_delay),
+        retries,
+        network.retries
+    );
+    tokio::time::sleep(network.retry_delay).await;
+    true
+}
+
 pub async fn run_receiver(
-    output_dir: &std::path::PathBuf,
-    password: &str,
+    output_dir: &Path,
+    password: &SecretString,
     connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut stream = establish_connection(&connection_mode).await?;
+    let mut resume = receive::ResumeState::default();
+    let mut retries = 0;
 
-    let (key, num_files, is_folder, folder_name) =
-        utils::receive_handshake(&mut stream, VERSION, password).await?;
+    loop {
+        let result = receive_session(
+            output_dir,
+            password,
+            &connection_mode,
+            network,
+            security,
+            &mut resume,
+        )
+        .await;
 
-    println!("Receiving {} file(s)...\n", num_files);
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("\nTransfer error: {}", e);
+                if !retry_after(e.as_ref(), network, &mut retries).await {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
 
-    let final_output_dir = if is_folder {
-        let folder_name = folder_name.ok_or("Folder name missing")?;
-        let mut folder_path = output_dir.clone();
-        folder_path.push(&folder_name);
-        println!("Creating folder: {}\n", folder_name);
-        if !folder_path.exists() {
-            std::fs::create_dir_all(&folder_path)?;
+async fn receive_session(
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: &ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+    resume: &mut receive::ResumeState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        connection_mode,
+        mdns::Role::Receiver,
+        password,
+        network,
+        security,
+    )
+    .await?;
+
+    let (mut key, sas, manifest) = with_handshake_timeout(
+        network,
+        utils::receive_handshake(&mut stream, password, security),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
+        receive_files(&mut stream, &mut key, manifest, output_dir, resume).await
+    })
+    .await;
+    match result {
+        Ok(()) => {
+            status!("===========================================");
+            status!("Transfer complete!");
+            status!("===========================================");
+            stream.shutdown().await?;
+            Ok(())
+        }
+        Err(e) => {
+            protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await;
+            Err(e)
         }
-        folder_path
+    }
+}
+
+/// Receives the files announced by `manifest`, after telling the sender where to continue.
+async fn receive_files(
+    stream: &mut net::TimeoutStream,
+    key: &mut utils::TrafficKey,
+    manifest: protocol::Manifest,
+    output_dir: &Path,
+    resume: &mut receive::ResumeState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Tell the sender where to continue; a different transfer starts from scratch
+    let first_file = if key.resumes() {
+        resume.begin_session(&manifest)
     } else {
-        output_dir.clone()
+        0
     };
+    let protocol::Manifest {
+        files: num_files,
+        folder: folder_name,
+        ..
+    } = manifest;
+    let resume_at = protocol::Message::Ack(protocol::Ack::Resume(first_file));
+    protocol::write_message(stream, &resume_at).await?;
 
-    let check_duplicate = num_files == 1;
-
-    for i in 0..num_files {
-        println!("===========================================");
-        println!("File {} of {}", i + 1, num_files);
-        println!("===========================================");
-        receive::receive_file(&mut stream, &final_output_dir, &key, check_duplicate).await?;
-        println!();
+    if first_file > 0 {
+        status!("Resuming at file {} of {}...\n", first_file + 1, num_files);
+    } else {
+        status!("Receiving {} file(s)...\n", num_files);
     }
 
-    println!("===========================================");
-    println!("Transfer complete!");
-    println!("===========================================");
+    let final_output_dir = match folder_name {
+        Some(folder_name) => {
+            let folder_path = receive::local_folder(output_dir, &folder_name).ok_or_else(|| {
+                protocol::Reason::new(
+                    protocol::ReasonCode::Protocol,
+                    format!("Invalid folder name from sender: {}", folder_name),
+                )
+            })?;
+            status!("Creating folder: {}\n", folder_name);
+            if !folder_path.exists() {
+                std::fs::create_dir_all(&folder_path).map_err(|e| {
+                    protocol::Reason::io(e, format_args!("creating folder {}", folder_name))
+                })?;
+            }
+            folder_path
+        }
+        None => output_dir.to_path_buf(),
+    };
+
+    let existing = if num_files == 1 {
+        receive::Existing::SkipIdentical
+    } else {
+        receive::Existing::Rename
+    };
+
+    for i in first_file..num_files {
+        status!("===========================================");
+        status!("File {} of {}", i + 1, num_files);
+        status!("===========================================");
+        receive::receive_file(stream, &final_output_dir, key, existing, resume).await?;
+        status!();
+    }
 
-    stream.shutdown().await?;
     Ok(())
 }
 
-fn collect_files(
-    dir: &std::path::Path,
-    files: &mut Vec<std::path::PathBuf>,
-) -> std::io::Result<()> {
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
     if dir.is_file() {
         files.push(dir.to_path_buf());
         return Ok(());
@@ -172,158 +482,908 @@ fn collect_files(
     Ok(())
 }
 
-pub async fn run_sender(
-    file_path: &std::path::PathBuf,
-    password: &str,
-    connection_mode: ConnectionMode,
-    persistent: bool,
+/// Builds the manifest for sending `files`, named relative to `base_path`.
+fn announce(
+    files: &[PathBuf],
+    base_path: &Path,
+    folder: Option<String>,
+) -> Result<protocol::Manifest, Box<dyn std::error::Error>> {
+    let mut listing = Vec::with_capacity(files.len());
+    for file in files {
+        let size = file
+            .metadata()
+            .map_err(|e| protocol::Reason::io(e, format_args!("reading {}", file.display())))?
+            .len();
+        listing.push((send::relative_name(file, base_path), size));
+    }
+    Ok(protocol::Manifest::new(
+        folder,
+        listing.iter().map(|(name, size)| (name.as_str(), *size)),
+    ))
+}
+
+/// Files to send, the folder their names are relative to, and the manifest announcing them.
+struct FileSet {
+    files: Vec<PathBuf>,
+    base_path: PathBuf,
+    manifest: protocol::Manifest,
+}
+
+impl FileSet {
+    fn collect(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+
+        if files.is_empty() {
+            return Err("No files to send".into());
+        }
+
+        let base_path = if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            path.parent().unwrap_or(Path::new("")).to_path_buf()
+        };
+
+        let folder = if path.is_dir() {
+            Some(
+                path.file_name()
+                    .ok_or("Invalid folder name")?
+                    .to_string_lossy()
+                    .to_string(),
+            )
+        } else {
+            None
+        };
+
+        let manifest = announce(&files, &base_path, folder)?;
+        Ok(FileSet {
+            files,
+            base_path,
+            manifest,
+        })
+    }
+}
+
+/// Sends the files the receiver still needs.
+async fn send_files(
+    stream: &mut net::TimeoutStream,
+    key: &mut utils::TrafficKey,
+    file_set: &FileSet,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut files = Vec::new();
-    collect_files(file_path, &mut files)?;
+    let files = &file_set.files;
+    let first_file = receive_resume_position(stream, key).await? as usize;
+    if first_file > files.len() {
+        return Err("Invalid resume position from receiver".into());
+    }
+    if first_file > 0 {
+        status!("Receiver already has {} file(s), resuming.", first_file);
+    }
 
-    if files.is_empty() {
-        return Err("No files to send".into());
+    for (i, file) in files.iter().enumerate().skip(first_file) {
+        status!("\n===========================================");
+        status!("File {} of {}", i + 1, files.len());
+        status!("===========================================");
+        send::send_from_path(stream, file, &file_set.base_path, key).await?;
     }
+    Ok(())
+}
 
-    let base_path = if file_path.is_dir() {
-        file_path.clone()
-    } else {
-        file_path
-            .parent()
-            .unwrap_or(std::path::Path::new(""))
-            .to_path_buf()
-    };
+/// Reads the index of the first file the receiver still needs.
+async fn receive_resume_position(
+    stream: &mut net::TimeoutStream,
+    key: &utils::TrafficKey,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    match protocol::receive(stream, key.alerts()).await? {
+        protocol::Message::Ack(protocol::Ack::Resume(first_file))
+            if first_file == 0 || key.resumes() =>
+        {
+            Ok(first_file)
+        }
+        protocol::Message::Ack(protocol::Ack::Resume(_)) => {
+            Err("Receiver resumed a transfer without agreeing to resume".into())
+        }
+        other => Err(protocol::unexpected("resume position", &other)),
+    }
+}
 
-    let is_folder = file_path.is_dir();
-    let folder_name = if is_folder {
-        file_path
-            .file_name()
-            .ok_or("Invalid folder name")?
-            .to_string_lossy()
-            .to_string()
-    } else {
-        String::new()
-    };
+pub async fn run_sender(
+    file_path: &Path,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    persistent: bool,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_set = FileSet::collect(file_path)?;
+
+    // Rotated when too many peers fail to authenticate
+    let mut password = password.clone();
 
-    let listener = if persistent && matches!(connection_mode, ConnectionMode::Listen) {
-        let l = utils::create_listener(DEFAULT_PORT)?;
-        mdns::advertise_service(DEFAULT_PORT)?;
-        Some(l)
+    // Keep one listener across retries so reconnecting peers find the same port.
+    // The advertisement lives exactly as long as the listener
+    let mut listener = if (persistent || network.retries > 0)
+        && matches!(connection_mode, ConnectionMode::Listen)
+    {
+        let l = net::create_listener(DEFAULT_PORT, &network.bind)?;
+        let metadata = service_metadata(mdns::Role::Sender, &password, network, security);
+        let advertisement =
+            mdns::Advertisement::start(DEFAULT_PORT, &network.bind, metadata, network.discovery)?;
+        Some((l, advertisement, security::AccessGuard::new(security)))
     } else {
         None
     };
 
     let mut transfer_count = 0u32;
+    let mut retries = 0;
     loop {
         transfer_count += 1;
 
         if persistent {
-            println!("\n===========================================");
-            println!("Transfer #{}", transfer_count);
-            println!("===========================================");
+            status!("\n===========================================");
+            status!("Transfer #{}", transfer_count);
+            status!("===========================================");
         }
 
-        let mut stream = if let Some(ref listener) = listener {
-            println!(
-                "Listening on [::]:{} (IPv4/IPv6 dual-stack)...",
-                DEFAULT_PORT
-            );
-            println!("Waiting for peer to connect...\n");
-            let (stream, socket_addr) = listener.accept().await?;
-            println!("Connection accepted from {}\n", socket_addr);
-            stream
-        } else {
-            establish_connection(&connection_mode).await?
-        };
-
-        let transfer_result = async {
-            let folder_name_opt = if is_folder {
-                Some(folder_name.as_str())
+        let connection =
+            if let Some((ref listener, ref mut advertisement, ref mut guard)) = listener {
+                if let Err(e) = advertisement.set_busy(false) {
+                    eprintln!("Failed to update mDNS status: {}", e);
+                }
+                status!("Listening on {}...", listener.describe());
+                status!("Waiting for peer to connect...\n");
+                let connection = loop {
+                    let accepted = tokio::select! {
+                        accepted = listener.accept(network) => accepted,
+                        _ = tokio::signal::ctrl_c() => {
+                            status!("\nStopping listener...");
+                            let reason = protocol::Reason::new(
+                                protocol::ReasonCode::Interrupted,
+                                "Interrupted by the user",
+                            );
+                            return Err(reason.into());
+                        }
+                    };
+                    match accepted {
+                        Ok((_, socket_addr)) if !guard.admit(socket_addr) => continue,
+                        accepted => break accepted,
+                    }
+                };
+                if let Err(e) = advertisement.set_busy(true) {
+                    eprintln!("Failed to update mDNS status: {}", e);
+                }
+                connection.map(|(stream, socket_addr)| {
+                    status!("Connection accepted from {}\n", socket_addr);
+                    (stream, Some(socket_addr))
+                })
             } else {
-                None
+                establish_connection(
+                    &connection_mode,
+                    mdns::Role::Sender,
+                    &password,
+                    network,
+                    security,
+                )
+                .await
+                .map(|stream| (stream, None))
             };
 
-            let key = utils::send_handshake(
-                &mut stream,
-                VERSION,
-                password,
-                files.len() as u64,
-                is_folder,
-                folder_name_opt,
+        let (mut stream, peer_addr) = match connection {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("\nConnection error: {}", e);
+                if !persistent && retry_after(e.as_ref(), network, &mut retries).await {
+                    continue;
+                }
+                return Err(e);
+            }
+        };
+
+        let transfer_result = async {
+            let (mut key, sas) = with_handshake_timeout(
+                network,
+                utils::send_handshake(&mut stream, &password, security, file_set.manifest.clone()),
             )
             .await?;
-
-            let check_duplicate = files.len() == 1;
-            for (i, file) in files.iter().enumerate() {
-                println!("\n===========================================");
-                println!("File {} of {}", i + 1, files.len());
-                println!("===========================================");
-                send::send_from_path(&mut stream, file, &base_path, &key, check_duplicate).await?;
+            if let (Some((_, _, guard)), Some(addr)) = (listener.as_mut(), peer_addr) {
+                guard.record_success(addr);
             }
 
-            Ok::<(), Box<dyn std::error::Error>>(())
+            let result = interruptible(async {
+                security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
+                send_files(&mut stream, &mut key, &file_set).await
+            })
+            .await;
+            if let Err(e) = &result {
+                protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await;
+            }
+            result
         }
         .await;
 
+        let _ = stream.shutdown().await;
+
         match transfer_result {
             Ok(_) => {
-                println!("\n===========================================");
-                println!("Transfer complete!");
-                println!("===========================================");
+                status!("\n===========================================");
+                status!("Transfer complete!");
+                status!("===========================================");
             }
             Err(e) => {
                 eprintln!("\nTransfer error: {}", e);
-                if !persistent {
+                if let (Some(auth_error), Some((_, advertisement, guard)), Some(addr)) =
+                    (e.downcast_ref(), listener.as_mut(), peer_addr)
+                {
+                    record_auth_failure(
+                        guard,
+                        advertisement,
+                        &mut password,
+                        security,
+                        addr,
+                        auth_error,
+                    )?;
+                }
+                if is_interrupted(e.as_ref()) {
+                    return Err(e);
+                } else if persistent {
+                    eprintln!("Waiting for next connection...");
+                } else if retry_after(e.as_ref(), network, &mut retries).await {
+                    continue;
+                } else {
                     return Err(e);
                 }
-                eprintln!("Waiting for next connection...");
             }
         }
 
-        let _ = stream.shutdown().await;
-
         if !persistent {
             break;
         }
-        println!("\nWaiting for next connection...");
+        status!("\nWaiting for next connection...");
     }
 
     Ok(())
 }
 
+/// Counts a failed authentication against the listener's budget, then rotates the
+/// password or fails once the budget is spent.
+fn record_auth_failure(
+    guard: &mut security::AccessGuard,
+    advertisement: &mut mdns::Advertisement,
+    password: &mut SecretString,
+    security: &security::SecurityOptions,
+    addr: std::net::SocketAddr,
+    error: &security::AuthError,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match guard.record_failure(addr, error) {
+        security::FailureVerdict::Continue => {}
+        // Paired devices have no password to guess, so only the per-peer backoff applies
+        security::FailureVerdict::Exhausted(security::FailureAction::Rotate)
+            if security.trusted.is_some() => {}
+        security::FailureVerdict::Exhausted(security::FailureAction::Rotate) => {
+            *password = utils::generate_password(security.password_format)?;
+            guard.reset();
+            if let Err(e) = advertisement.set_session(utils::session_tag(password.expose())) {
+                eprintln!("Failed to update mDNS session: {}", e);
+            }
+            security::log_event("Too many failed attempts, password rotated");
+            status!("New password: {}", password.expose());
+        }
+        security::FailureVerdict::Exhausted(_) => {
+            security::log_event("Too many failed attempts, shutting down");
+            return Err("Too many failed authentication attempts".into());
+        }
+    }
+    Ok(())
+}
+
+/// Serves `root` read-only to `flying get` clients until Ctrl-C.
+pub async fn run_share(
+    root: &Path,
+    password: &SecretString,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let root = root.canonicalize()?;
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", root.display()).into());
+    }
+    // Clients are served side by side as local tasks
+    tokio::task::LocalSet::new()
+        .run_until(share::run(root, password, network, security))
+        .await
+}
+
+/// Listens for `receivers` receivers and sends `file_path` to all of them at once.
+///
+/// With `start_after`, the transfer starts after that long with the receivers that
+/// are ready by then.
+pub async fn run_broadcast(
+    file_path: &Path,
+    password: &SecretString,
+    receivers: usize,
+    start_after: Option<Duration>,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_set = FileSet::collect(file_path)?;
+    // Sessions run as local tasks, so their errors need not be `Send`
+    tokio::task::LocalSet::new()
+        .run_until(fanout::serve(
+            file_set,
+            password,
+            receivers,
+            start_after,
+            network,
+            security,
+        ))
+        .await
+}
+
 pub async fn run_sender_from_handle(
     file: std::fs::File,
     filename: &str,
-    password: &str,
+    password: &SecretString,
     connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let size = file.metadata()?.len();
-    let mut stream = establish_connection(&connection_mode).await?;
+    let mut retries = 0;
+
+    loop {
+        let transfer_result = async {
+            let mut stream = establish_connection(
+                &connection_mode,
+                mdns::Role::Sender,
+                password,
+                network,
+                security,
+            )
+            .await?;
+
+            let (mut key, sas) = with_handshake_timeout(
+                network,
+                utils::send_handshake(
+                    &mut stream,
+                    password,
+                    security,
+                    protocol::Manifest::new(None, std::iter::once((filename, size))),
+                ),
+            )
+            .await?;
 
-    let transfer_result = async {
-        let key = utils::send_handshake(&mut stream, VERSION, password, 1, false, None).await?;
+            let result = interruptible(async {
+                security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
 
-        println!("\n===========================================");
-        println!("File 1 of 1");
-        println!("===========================================");
-        send::send_file(&mut stream, file, filename, size, &key, true).await?;
+                if receive_resume_position(&mut stream, &key).await? == 0 {
+                    status!("\n===========================================");
+                    status!("File 1 of 1");
+                    status!("===========================================");
+                    let file = file.try_clone()?;
+                    send::send_file(&mut stream, file, filename, size, &mut key).await?;
+                }
+                Ok(())
+            })
+            .await;
+            if let Err(e) = &result {
+                protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await;
+                return result;
+            }
+
+            stream.shutdown().await?;
+            Ok::<(), Box<dyn std::error::Error>>(())
+        }
+        .await;
 
-        Ok::<(), Box<dyn std::error::Error>>(())
+        match transfer_result {
+            Ok(_) => {
+                status!("\n===========================================");
+                status!("Transfer complete!");
+                status!("===========================================");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("\nTransfer error: {}", e);
+                if !retry_after(e.as_ref(), network, &mut retries).await {
+                    return Err(e);
+                }
+            }
+        }
     }
+}
+
+/// Sends everything read from stdin as one file named `filename`. A stream cannot be
+/// read twice, so there are no retries.
+pub async fn run_sender_from_stdin(
+    filename: &str,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        &connection_mode,
+        mdns::Role::Sender,
+        password,
+        network,
+        security,
+    )
+    .await?;
+
+    let (mut key, sas) = with_handshake_timeout(
+        network,
+        utils::send_handshake(
+            &mut stream,
+            password,
+            security,
+            protocol::Manifest::new(None, std::iter::once((filename, protocol::UNKNOWN_SIZE))),
+        ),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
+        if receive_resume_position(&mut stream, &key).await? != 0 {
+            return Err("A stream cannot be resumed".into());
+        }
+        send::send_stream(&mut stream, std::io::stdin(), filename, &mut key).await
+    })
     .await;
+    if let Err(e) = result {
+        protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await;
+        return Err(e);
+    }
+    stream.shutdown().await?;
 
-    match transfer_result {
-        Ok(_) => {
-            println!("\n===========================================");
-            println!("Transfer complete!");
-            println!("===========================================");
+    status!("\n===========================================");
+    status!("Transfer complete!");
+    status!("===========================================");
+    Ok(())
+}
+
+/// Receives a single file or stream and writes it to stdout. Data already written
+/// cannot be taken back, so there are no retries.
+pub async fn run_receiver_to_stdout(
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        &connection_mode,
+        mdns::Role::Receiver,
+        password,
+        network,
+        security,
+    )
+    .await?;
+
+    let (mut key, sas, manifest) = with_handshake_timeout(
+        network,
+        utils::receive_handshake(&mut stream, password, security),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, key.alerts(), security).await?;
+        if manifest.files != 1 || manifest.folder.is_some() {
+            return Err(protocol::Reason::new(
+                protocol::ReasonCode::Rejected,
+                "Only a single file can be written to stdout",
+            )
+            .into());
+        }
+        let resume_at = protocol::Message::Ack(protocol::Ack::Resume(0));
+        protocol::write_message(&mut stream, &resume_at).await?;
+
+        let mut out = std::io::BufWriter::new(std::io::stdout());
+        receive::receive_to_writer(&mut stream, &mut out, &mut key).await
+    })
+    .await;
+    if let Err(e) = result {
+        protocol::report_failure(&mut stream, key.alerts(), e.as_ref()).await;
+        return Err(e);
+    }
+    stream.shutdown().await?;
+
+    status!("===========================================");
+    status!("Transfer complete!");
+    status!("===========================================");
+    Ok(())
+}
+
+/// Sends `path` to the peer and receives the peer's files into `output_dir`, over
+/// one connection and one password.
+///
+/// The listener sends first, then the two sides swap. Each direction resumes on
+/// its own after a reconnect.
+pub async fn run_exchange(
+    path: &Path,
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let file_set = FileSet::collect(path)?;
+    let mut resume = receive::ResumeState::default();
+    let mut retries = 0;
+
+    loop {
+        let result = exchange_session(
+            &file_set,
+            output_dir,
+            password,
+            &connection_mode,
+            network,
+            security,
+            &mut resume,
+        )
+        .await;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("\nTransfer error: {}", e);
+                if !retry_after(e.as_ref(), network, &mut retries).await {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+async fn exchange_session(
+    file_set: &FileSet,
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: &ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+    resume: &mut receive::ResumeState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        connection_mode,
+        mdns::Role::Exchange,
+        password,
+        network,
+        security,
+    )
+    .await?;
+    let is_listener = matches!(connection_mode, ConnectionMode::Listen);
+
+    let (mut outgoing, mut incoming, sas, peer_manifest) = with_handshake_timeout(
+        network,
+        utils::exchange_handshake(
+            &mut stream,
+            password,
+            security,
+            file_set.manifest.clone(),
+            is_listener,
+        ),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, incoming.alerts(), security).await?;
+        if is_listener {
+            status!("Sending {} file(s)...", file_set.files.len());
+            send_files(&mut stream, &mut outgoing, file_set).await?;
+            status!();
+            receive_files(
+                &mut stream,
+                &mut incoming,
+                peer_manifest,
+                output_dir,
+                resume,
+            )
+            .await
+        } else {
+            receive_files(
+                &mut stream,
+                &mut incoming,
+                peer_manifest,
+                output_dir,
+                resume,
+            )
+            .await?;
+            status!("Sending {} file(s)...", file_set.files.len());
+            send_files(&mut stream, &mut outgoing, file_set).await
+        }
+    })
+    .await;
+    match result {
+        Ok(()) => {
+            status!("\n===========================================");
+            status!("Exchange complete!");
+            status!("===========================================");
+            stream.shutdown().await?;
+            Ok(())
         }
         Err(e) => {
-            eprintln!("\nTransfer error: {}", e);
-            return Err(e);
+            protocol::report_failure(&mut stream, outgoing.alerts(), e.as_ref()).await;
+            Err(e)
+        }
+    }
+}
+
+/// Mirrors `dir` to a `flying receive --sync`, then pushes every later change until
+/// Ctrl-C. With `delete`, files deleted here are deleted on the mirror too.
+pub async fn run_sync(
+    dir: &Path,
+    delete: bool,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let root = dir.canonicalize()?;
+    if !root.is_dir() {
+        return Err(format!("{} is not a folder", root.display()).into());
+    }
+    let mut retries = 0;
+
+    loop {
+        let result =
+            sync_session(&root, delete, password, &connection_mode, network, security).await;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("\nSync error: {}", e);
+                if !retry_after(e.as_ref(), network, &mut retries).await {
+                    return Err(e);
+                }
+            }
         }
     }
+}
+
+async fn sync_session(
+    root: &Path,
+    delete: bool,
+    password: &SecretString,
+    connection_mode: &ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        connection_mode,
+        mdns::Role::Sync,
+        password,
+        network,
+        security,
+    )
+    .await?;
+    let is_listener = matches!(connection_mode, ConnectionMode::Listen);
+
+    let (mut outgoing, incoming, sas) = with_handshake_timeout(
+        network,
+        utils::duplex_handshake(
+            &mut stream,
+            password,
+            security,
+            protocol::Mode::Sync,
+            is_listener,
+        ),
+    )
+    .await?;
+
+    let result = async {
+        interruptible(async {
+            security::verify_peer(&mut stream, &sas, incoming.alerts(), security).await?;
+            sync::initial_pass(&mut stream, &mut outgoing, root, delete).await
+        })
+        .await?;
+        sync::watch(&mut stream, &mut outgoing, root, delete).await
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            status!("\nStopped syncing.");
+            stream.shutdown().await?;
+            Ok(())
+        }
+        Err(e) => {
+            protocol::report_failure(&mut stream, outgoing.alerts(), e.as_ref()).await;
+            Err(e)
+        }
+    }
+}
+
+/// Keeps a copy of a `flying sync` folder under `output_dir` until the source stops.
+pub async fn run_mirror(
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut retries = 0;
+
+    loop {
+        let result =
+            mirror_session(output_dir, password, &connection_mode, network, security).await;
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                eprintln!("\nSync error: {}", e);
+                if !retry_after(e.as_ref(), network, &mut retries).await {
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+async fn mirror_session(
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: &ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        connection_mode,
+        mdns::Role::Mirror,
+        password,
+        network,
+        security,
+    )
+    .await?;
+    let is_listener = matches!(connection_mode, ConnectionMode::Listen);
 
+    let (outgoing, mut incoming, sas) = with_handshake_timeout(
+        network,
+        utils::duplex_handshake(
+            &mut stream,
+            password,
+            security,
+            protocol::Mode::Mirror,
+            is_listener,
+        ),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, incoming.alerts(), security).await?;
+        sync::mirror(&mut stream, &mut incoming, output_dir).await
+    })
+    .await;
+    match result {
+        Ok(()) => {
+            status!("\nThe source stopped syncing.");
+            stream.shutdown().await?;
+            Ok(())
+        }
+        Err(e) => {
+            protocol::report_failure(&mut stream, outgoing.alerts(), e.as_ref()).await;
+            Err(e)
+        }
+    }
+}
+
+/// Connects to a `flying share` and downloads `paths` into `output_dir`, or opens an
+/// interactive shell when no paths are given.
+pub async fn run_get(
+    paths: &[String],
+    output_dir: &Path,
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = establish_connection(
+        &connection_mode,
+        mdns::Role::Get,
+        password,
+        network,
+        security,
+    )
+    .await?;
+    let (mut outgoing, mut incoming, sas) = with_handshake_timeout(
+        network,
+        utils::duplex_handshake(&mut stream, password, security, protocol::Mode::Get, false),
+    )
+    .await?;
+
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, incoming.alerts(), security).await?;
+        let mut client = share::Client {
+            stream: &mut stream,
+            outgoing: &mut outgoing,
+            incoming: &mut incoming,
+            output_dir,
+        };
+        if paths.is_empty() {
+            return share::shell(&mut client).await;
+        }
+
+        let mut refused = 0;
+        for path in paths {
+            if let Err(reason) = client.get(path).await? {
+                eprintln!("{}", reason);
+                refused += 1;
+            }
+        }
+        if refused > 0 {
+            return Err(format!(
+                "{} of {} path(s) could not be downloaded",
+                refused,
+                paths.len()
+            )
+            .into());
+        }
+        Ok(())
+    })
+    .await;
+    match result {
+        Ok(()) => {
+            stream.shutdown().await?;
+            Ok(())
+        }
+        Err(e) => {
+            protocol::report_failure(&mut stream, outgoing.alerts(), e.as_ref()).await;
+            Err(e)
+        }
+    }
+}
+
+/// Pairs with another device using `password` and pins its identity key.
+///
+/// Both users must confirm the verification code, since the pairing is trusted for
+/// every later session.
+pub async fn run_pair(
+    password: &SecretString,
+    connection_mode: ConnectionMode,
+    network: &net::NetworkOptions,
+) -> Result<identity::TrustedDevice, Box<dyn std::error::Error>> {
+    let identity = identity::Identity::load_or_create()?;
+    let mut store = identity::TrustStore::load()?;
+    let security = security::SecurityOptions {
+        confirm_sas: true,
+        ..Default::default()
+    };
+
+    let mut stream = establish_connection(
+        &connection_mode,
+        mdns::Role::Pair,
+        password,
+        network,
+        &security,
+    )
+    .await?;
+    let is_listener = matches!(connection_mode, ConnectionMode::Listen);
+
+    let (key, sas) = with_handshake_timeout(
+        network,
+        utils::pair_handshake(&mut stream, password, is_listener),
+    )
+    .await?;
+
+    let alerts = utils::AlertKey::new(&key, protocol::Mode::Pair, is_listener)?;
+    let result = interruptible(async {
+        security::verify_peer(&mut stream, &sas, &alerts, &security).await?;
+
+        let device_name =
+            mdns::ServiceMetadata::new(mdns::Role::Pair, network.device_name.as_deref(), None)
+                .device_name;
+        with_handshake_timeout(
+            network,
+            utils::exchange_identities(&mut stream, &key, &identity, &device_name, is_listener),
+        )
+        .await
+    })
+    .await;
+    let device = match result {
+        Ok(device) => device,
+        Err(e) => {
+            protocol::report_failure(&mut stream, &alerts, e.as_ref()).await;
+            return Err(e);
+        }
+    };
     stream.shutdown().await?;
-    Ok(())
+
+    store.add(device.clone())?;
+    Ok(device)
 }
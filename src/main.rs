@@ -1,6 +1,19 @@
-use clap::{Parser, Subcommand};
-use flying::{ConnectionMode, run_receiver, run_sender, utils};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, builder::Resettable};
+use flying::{
+    ConnectionMode,
+    identity::{Identity, TrustStore, TrustedSession},
+    mdns::DiscoveryMethod,
+    net::{InterfaceSelector, IpNetwork, NetworkOptions},
+    run_broadcast, run_exchange, run_get, run_mirror, run_pair, run_receiver,
+    run_receiver_to_stdout, run_sender, run_sender_from_stdin, run_share, run_sync,
+    security::{FailureAction, SecretString, SecurityOptions},
+    status,
+    utils::{self, Cipher, PasswordFormat},
+};
+use std::{path::PathBuf, time::Duration};
+use zeroize::Zeroizing;
+
+const GENERATED_PASSWORD_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(300);
 
 #[derive(Parser, Debug)]
 #[command(name = "flying")]
@@ -10,9 +23,159 @@ struct Cli {
     command: Commands,
 }
 
+#[derive(Args, Debug)]
+struct NetworkArgs {
+    #[arg(long, value_name = "IP|IFACE", requires = "listen")]
+    bind: Vec<InterfaceSelector>,
+    #[arg(long, value_name = "IP|IFACE", conflicts_with = "listen")]
+    source: Option<InterfaceSelector>,
+    #[arg(long, value_name = "NAME")]
+    name: Option<String>,
+    #[arg(long, value_name = "mdns|broadcast|both", default_value = "both")]
+    discovery: DiscoveryMethod,
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    discovery_timeout: Option<Duration>,
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = humantime::parse_duration)]
+    connect_timeout: Duration,
+    #[arg(long, value_name = "DURATION", requires = "listen", value_parser = humantime::parse_duration)]
+    accept_timeout: Option<Duration>,
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = humantime::parse_duration)]
+    handshake_timeout: Duration,
+    #[arg(long, value_name = "DURATION", default_value = "60s", value_parser = humantime::parse_duration)]
+    idle_timeout: Duration,
+    #[arg(long, value_name = "DURATION", default_value = "15s", value_parser = humantime::parse_duration)]
+    keepalive: Duration,
+    #[arg(long = "retry", value_name = "N", default_value_t = 0)]
+    retries: u32,
+    #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = humantime::parse_duration)]
+    retry_delay: Duration,
+}
+
+impl From<NetworkArgs> for NetworkOptions {
+    fn from(args: NetworkArgs) -> Self {
+        NetworkOptions {
+            bind: args.bind,
+            source: args.source,
+            device_name: args.name,
+            discovery: args.discovery,
+            discovery_timeout: args
+                .discovery_timeout
+                .unwrap_or(NetworkOptions::default().discovery_timeout),
+            connect_timeout: args.connect_timeout,
+            accept_timeout: args.accept_timeout,
+            handshake_timeout: args.handshake_timeout,
+            idle_timeout: args.idle_timeout,
+            keepalive: args.keepalive,
+            retries: args.retries,
+            retry_delay: args.retry_delay,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct SecurityArgs {
+    #[arg(long)]
+    confirm_sas: bool,
+    #[arg(long, conflicts_with = "password")]
+    trusted: bool,
+    #[arg(long, value_name = "NAME|FINGERPRINT", conflicts_with = "password")]
+    peer: Option<String>,
+    #[arg(long, value_name = "aes-256-gcm|chacha20-poly1305")]
+    cipher: Option<Cipher>,
+    #[arg(long, value_name = "SIZE", default_value = "64GiB", value_parser = parse_size)]
+    rekey_after: u64,
+    #[arg(long, value_name = "words|numeric|wormhole", default_value = "words")]
+    password_format: PasswordFormat,
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=32))]
+    password_length: Option<u8>,
+    #[arg(long, value_name = "CIDR", requires = "listen")]
+    allow: Vec<IpNetwork>,
+    #[arg(long, value_name = "CIDR", requires = "listen")]
+    deny: Vec<IpNetwork>,
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    max_failures: u32,
+    /// Default: rotate, or shutdown with --hide-password
+    #[arg(long, value_name = "shutdown|rotate")]
+    on_max_failures: Option<FailureAction>,
+    #[arg(long, value_name = "DURATION", default_value = "2s", value_parser = humantime::parse_duration)]
+    failure_backoff: Duration,
+    /// Leave the password out of the session banner
+    #[arg(long)]
+    hide_password: bool,
+}
+
+/// Parses a byte count such as `4096`, `512MiB` or `2G`, using powers of 1024.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", s))?;
+    let shift = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => {
+            return Err(format!(
+                "Unknown size unit in '{}' (expected K, M, G or T)",
+                s
+            ));
+        }
+    };
+    match number.checked_mul(1 << shift) {
+        Some(0) => Err("Size must be greater than zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err(format!("Size '{}' is too large", s)),
+    }
+}
+
+impl TryFrom<SecurityArgs> for SecurityOptions {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(args: SecurityArgs) -> Result<Self, Self::Error> {
+        let trusted = if args.trusted || args.peer.is_some() {
+            Some(TrustedSession::load(args.peer.as_deref())?)
+        } else {
+            None
+        };
+
+        Ok(SecurityOptions {
+            confirm_sas: args.confirm_sas,
+            trusted,
+            cipher: args.cipher,
+            rekey_after: args.rekey_after,
+            password_format: match (args.password_length, args.password_format) {
+                (Some(_), PasswordFormat::Wormhole) => {
+                    return Err("--password-length cannot be used with wormhole codes".into());
+                }
+                (Some(length), format) => format.with_length(length),
+                (None, format) => format,
+            },
+            allow: args.allow,
+            deny: args.deny,
+            max_failures: args.max_failures,
+            // A rotated password is printed, which --hide-password must not do
+            on_max_failures: match (args.on_max_failures, args.hide_password) {
+                (Some(FailureAction::Rotate), true) => {
+                    return Err(
+                        "--hide-password cannot be used with --on-max-failures rotate".into(),
+                    );
+                }
+                (Some(action), _) => action,
+                (None, true) => FailureAction::Shutdown,
+                (None, false) => FailureAction::Rotate,
+            },
+            failure_backoff: args.failure_backoff,
+        })
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     Send {
+        /// File or folder to send, or - to send stdin
         file: PathBuf,
         #[arg(short, long, conflicts_with = "connect")]
         listen: bool,
@@ -22,6 +185,28 @@ enum Commands {
         recursive: bool,
         #[arg(short = 'P', long)]
         persistent: bool,
+        /// Wait for N receivers and send to all of them at once
+        #[arg(
+            long,
+            value_name = "N",
+            requires = "listen",
+            conflicts_with_all = ["persistent", "confirm_sas"],
+            value_parser = clap::value_parser!(u32).range(1..),
+        )]
+        receivers: Option<u32>,
+        /// Start with the receivers that are ready after this long
+        #[arg(long, value_name = "DURATION", requires = "receivers", value_parser = humantime::parse_duration)]
+        start_after: Option<Duration>,
+        /// Name the receiver saves stdin as
+        #[arg(long, value_name = "NAME", default_value = "stdin")]
+        filename: String,
+        #[command(flatten)]
+        network: NetworkArgs,
+        /// Generate the password on this side when discovering the listener (not with -c)
+        #[arg(long, conflicts_with_all = ["password", "connect"])]
+        generate: bool,
+        #[command(flatten)]
+        security: SecurityArgs,
         password: Option<String>,
     },
 
@@ -30,50 +215,238 @@ enum Commands {
         listen: bool,
         #[arg(short, long, value_name = "IP")]
         connect: Option<String>,
+        /// Mirror a folder from `flying sync`, applying its changes until it stops
+        #[arg(long)]
+        sync: bool,
+        /// Write the received file to stdout, with status messages on stderr
+        #[arg(long, conflicts_with_all = ["sync", "output"])]
+        stdout: bool,
+        #[command(flatten)]
+        network: NetworkArgs,
+        /// Generate the password on this side when discovering the listener (not with -c)
+        #[arg(long, conflicts_with_all = ["password", "connect"])]
+        generate: bool,
+        #[command(flatten)]
+        security: SecurityArgs,
+        password: Option<String>,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+
+    /// Send files and receive the other device's files in one session
+    Exchange {
+        path: PathBuf,
+        #[arg(short, long, conflicts_with = "connect")]
+        listen: bool,
+        #[arg(short, long, value_name = "IP")]
+        connect: Option<String>,
+        #[arg(short = 'r', long)]
+        recursive: bool,
+        #[command(flatten)]
+        network: NetworkArgs,
+        /// Generate the password on this side when discovering the listener (not with -c)
+        #[arg(long, conflicts_with_all = ["password", "connect"])]
+        generate: bool,
+        #[command(flatten)]
+        security: SecurityArgs,
+        password: Option<String>,
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+
+    /// Mirror a folder to `flying receive --sync` and keep pushing its changes
+    Sync {
+        dir: PathBuf,
+        #[arg(short, long, conflicts_with = "connect")]
+        listen: bool,
+        #[arg(short, long, value_name = "IP")]
+        connect: Option<String>,
+        /// Also delete files on the mirror that are deleted here
+        #[arg(long)]
+        delete: bool,
+        #[command(flatten)]
+        network: NetworkArgs,
+        /// Generate the password on this side when discovering the listener (not with -c)
+        #[arg(long, conflicts_with_all = ["password", "connect"])]
+        generate: bool,
+        #[command(flatten)]
+        security: SecurityArgs,
+        password: Option<String>,
+    },
+
+    /// Share a folder that other devices browse and download from with `flying get`
+    // A share always listens, so listener options need no -l
+    #[command(
+        mut_arg("bind", |arg| arg.requires(Resettable::Reset)),
+        mut_arg("accept_timeout", |arg| arg.requires(Resettable::Reset)),
+        mut_arg("allow", |arg| arg.requires(Resettable::Reset)),
+        mut_arg("deny", |arg| arg.requires(Resettable::Reset)),
+    )]
+    Share {
+        dir: PathBuf,
+        /// Only there so connecting options are rejected
+        #[arg(short, long, hide = true, default_value_t = true)]
+        listen: bool,
+        #[command(flatten)]
+        network: NetworkArgs,
+        #[command(flatten)]
+        security: SecurityArgs,
+        password: Option<String>,
+    },
+
+    /// Download from a device running `flying share`, or browse it when no paths are given
+    Get {
+        /// Only there so listener options are rejected; a client never listens
+        #[arg(short, long, hide = true)]
+        listen: bool,
+        #[arg(short, long, value_name = "IP")]
+        connect: Option<String>,
+        #[command(flatten)]
+        network: NetworkArgs,
+        #[command(flatten)]
+        security: SecurityArgs,
         password: Option<String>,
+        /// Files or folders to download, relative to the shared folder
+        paths: Vec<String>,
         #[arg(short, long, default_value = ".")]
         output: PathBuf,
     },
+
+    /// Pair with another device so later transfers need no password
+    Pair {
+        #[arg(short, long, conflicts_with = "connect")]
+        listen: bool,
+        #[arg(short, long, value_name = "IP")]
+        connect: Option<String>,
+        /// Show this device's fingerprint and the paired devices
+        #[arg(long, conflicts_with_all = ["listen", "connect", "forget"])]
+        list: bool,
+        /// Remove a paired device
+        #[arg(long, value_name = "NAME|FINGERPRINT", conflicts_with_all = ["listen", "connect"])]
+        forget: Option<String>,
+        #[command(flatten)]
+        network: NetworkArgs,
+        password: Option<String>,
+    },
+}
+
+fn list_paired_devices() -> Result<(), Box<dyn std::error::Error>> {
+    let identity = Identity::load_or_create()?;
+    let store = TrustStore::load()?;
+
+    status!("This device: {}", identity.fingerprint());
+    if store.devices().is_empty() {
+        status!("No paired devices.");
+    } else {
+        status!("Paired devices:");
+        for device in store.devices() {
+            status!("  {} ({})", device.name, device.fingerprint());
+        }
+    }
+    Ok(())
+}
+
+fn exit_on_error<T>(result: Result<T, Box<dyn std::error::Error>>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    })
 }
 
 fn print_session_info(
     mode: &str,
-    password: &str,
+    password: Option<&str>,
     connection_mode: &ConnectionMode,
+    network: &NetworkOptions,
+    security: &SecurityOptions,
     output_dir: Option<&PathBuf>,
 ) {
-    println!("===========================================");
-    println!("Flying - File Transfer Tool");
-    println!("===========================================");
-    println!("Mode: {}", mode);
-    println!("Password: {}", password);
+    status!("===========================================");
+    status!("Flying - File Transfer Tool");
+    status!("===========================================");
+    status!("Mode: {}", mode);
+    match &security.trusted {
+        Some(trusted) => {
+            let peers: Vec<&str> = trusted.peers.iter().map(|p| p.name.as_str()).collect();
+            status!("Authentication: paired device ({})", peers.join(", "));
+        }
+        None => status!("Password: {}", password.unwrap_or("(hidden)")),
+    }
+    if mode != "PAIR" {
+        match security.cipher {
+            Some(cipher) => status!("Cipher: {}", cipher),
+            None => status!(
+                "Cipher: negotiated, preferring {}",
+                Cipher::preferences(None)[0]
+            ),
+        }
+    }
     if let Some(dir) = output_dir {
-        println!("Output directory: {:?}", dir);
+        status!("Output directory: {:?}", dir);
     }
     match connection_mode {
         ConnectionMode::AutoDiscover => {
-            println!("Connection: Auto-discovering peers on local network")
+            status!("Connection: Auto-discovering peers on local network")
         }
         ConnectionMode::Listen => {
-            println!("Connection: Listening for incoming connections")
+            status!("Connection: Listening for incoming connections")
         }
-        ConnectionMode::Connect(ip) => println!("Connection: Will connect to {}", ip),
+        ConnectionMode::Connect(ip) => status!("Connection: Will connect to {}", ip),
+    }
+    if !network.bind.is_empty() {
+        let bind: Vec<String> = network.bind.iter().map(|b| b.to_string()).collect();
+        status!("Bind: {}", bind.join(", "));
+    }
+    if let Some(source) = &network.source {
+        status!("Source: {}", source);
     }
-    println!("===========================================\n");
+    status!("===========================================\n");
 }
 
-fn get_or_prompt_password(connection_mode: &ConnectionMode, password: Option<String>) -> String {
-    match connection_mode {
-        ConnectionMode::Listen => utils::generate_password(),
-        ConnectionMode::AutoDiscover | ConnectionMode::Connect(_) => {
-            password.unwrap_or_else(|| {
-                println!("Please enter password:");
-                let mut input = String::new();
-                std::io::stdin().read_line(&mut input).unwrap();
-                input.trim().to_string()
-            })
+fn get_or_prompt_password(
+    connection_mode: &ConnectionMode,
+    security: &SecurityOptions,
+    password: Option<String>,
+    generate: bool,
+    hide_password: bool,
+) -> Result<SecretString, Box<dyn std::error::Error>> {
+    if security.trusted.is_some() {
+        return Ok(SecretString::default());
+    }
+
+    let password = match password {
+        Some(password) => SecretString::new(password),
+        None if generate || matches!(connection_mode, ConnectionMode::Listen) => {
+            if hide_password {
+                return Err("A generated password cannot be hidden, pass your own instead".into());
+            }
+            return utils::generate_password(security.password_format);
+        }
+        None => {
+            status!("Please enter password:");
+            let mut input = Zeroizing::new(String::with_capacity(256));
+            std::io::stdin().read_line(&mut input)?;
+            SecretString::from(input.trim())
         }
+    };
+
+    if password.is_empty() {
+        return Err("Password must not be empty".into());
+    }
+    if let Some(warning) = utils::password_warning(password.expose()) {
+        eprintln!("{}", warning);
+    }
+    Ok(password)
+}
+
+/// A connecting side that generated the password waits until the other person has typed it.
+fn network_options(args: NetworkArgs, generate: bool) -> NetworkOptions {
+    let explicit_timeout = args.discovery_timeout.is_some();
+    let mut network = NetworkOptions::from(args);
+    if generate && !explicit_timeout {
+        network.discovery_timeout = GENERATED_PASSWORD_DISCOVERY_TIMEOUT;
     }
+    network
 }
 
 #[tokio::main]
@@ -87,13 +460,27 @@ async fn main() {
             connect,
             recursive,
             persistent,
+            receivers,
+            start_after,
+            filename,
+            network,
+            generate,
+            security,
             password,
         } => {
-            if !file.exists() {
+            let from_stdin = file.as_os_str() == "-";
+            if !from_stdin && !file.exists() {
                 eprintln!("Error: File/directory does not exist: {:?}", file);
                 std::process::exit(1);
             }
 
+            if from_stdin && (persistent || receivers.is_some()) {
+                eprintln!(
+                    "Error: stdin can only be sent once, without --persistent or --receivers"
+                );
+                std::process::exit(1);
+            }
+
             if file.is_dir() && !recursive {
                 eprintln!("Error: Cannot send directory without -r/--recursive flag");
                 std::process::exit(1);
@@ -105,10 +492,84 @@ async fn main() {
             }
 
             let connection_mode = ConnectionMode::from_params(listen, connect);
-            let password = get_or_prompt_password(&connection_mode, password);
-            print_session_info("SEND", &password, &connection_mode, None);
+            let network = network_options(network, generate && !listen);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            // Prompts read stdin, which holds the data
+            if from_stdin
+                && (security.confirm_sas
+                    || (password.is_none() && security.trusted.is_none() && !generate && !listen))
+            {
+                eprintln!(
+                    "Error: When sending stdin, pass the password and do not use --confirm-sas"
+                );
+                std::process::exit(1);
+            }
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                generate,
+                hide_password,
+            ));
+            // Choosing among the peers found also reads stdin
+            if from_stdin
+                && matches!(connection_mode, ConnectionMode::AutoDiscover)
+                && security.trusted.is_none()
+                && utils::session_tag(password.expose()).is_none()
+            {
+                eprintln!(
+                    "Error: When sending stdin with a self-chosen password, use -l or -c to pick the peer"
+                );
+                std::process::exit(1);
+            }
+            print_session_info(
+                "SEND",
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                None,
+            );
+            if generate && !listen {
+                status!("Share this password with the other device and start it with -l.\n");
+            }
 
-            if let Err(e) = run_sender(&file, &password, connection_mode, persistent).await {
+            let result = match receivers {
+                _ if from_stdin => {
+                    run_sender_from_stdin(
+                        &filename,
+                        &password,
+                        connection_mode,
+                        &network,
+                        &security,
+                    )
+                    .await
+                }
+                Some(receivers) => {
+                    run_broadcast(
+                        &file,
+                        &password,
+                        receivers as usize,
+                        start_after,
+                        &network,
+                        &security,
+                    )
+                    .await
+                }
+                None => {
+                    run_sender(
+                        &file,
+                        &password,
+                        connection_mode,
+                        persistent,
+                        &network,
+                        &security,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
                 eprintln!("Error: {}", e);
                 std::process::exit(1);
             }
@@ -117,22 +578,333 @@ async fn main() {
         Commands::Receive {
             listen,
             connect,
+            sync,
+            stdout,
+            network,
+            generate,
+            security,
             password,
             output,
         } => {
+            if stdout {
+                utils::reserve_stdout();
+            }
             if !output.exists() {
                 eprintln!("Error: Output directory does not exist: {:?}", output);
                 std::process::exit(1);
             }
 
             let connection_mode = ConnectionMode::from_params(listen, connect);
-            let password = get_or_prompt_password(&connection_mode, password);
-            print_session_info("RECEIVE", &password, &connection_mode, Some(&output));
+            let network = network_options(network, generate && !listen);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                generate,
+                hide_password,
+            ));
+            print_session_info(
+                if sync { "MIRROR" } else { "RECEIVE" },
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                (!stdout).then_some(&output),
+            );
+            if generate && !listen {
+                status!("Share this password with the other device and start it with -l.\n");
+            }
 
-            if let Err(e) = run_receiver(&output, &password, connection_mode).await {
+            let result = if stdout {
+                run_receiver_to_stdout(&password, connection_mode, &network, &security).await
+            } else if sync {
+                run_mirror(&output, &password, connection_mode, &network, &security).await
+            } else {
+                run_receiver(&output, &password, connection_mode, &network, &security).await
+            };
+            if let Err(e) = result {
                 eprintln!("Error: {}", e);
                 std::process::exit(1);
             }
         }
+
+        Commands::Exchange {
+            path,
+            listen,
+            connect,
+            recursive,
+            network,
+            generate,
+            security,
+            password,
+            output,
+        } => {
+            if !path.exists() {
+                eprintln!("Error: File/directory does not exist: {:?}", path);
+                std::process::exit(1);
+            }
+
+            if path.is_dir() && !recursive {
+                eprintln!("Error: Cannot send directory without -r/--recursive flag");
+                std::process::exit(1);
+            }
+
+            if !output.exists() {
+                eprintln!("Error: Output directory does not exist: {:?}", output);
+                std::process::exit(1);
+            }
+
+            let connection_mode = ConnectionMode::from_params(listen, connect);
+            let network = network_options(network, generate && !listen);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                generate,
+                hide_password,
+            ));
+            print_session_info(
+                "EXCHANGE",
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                Some(&output),
+            );
+            if generate && !listen {
+                status!("Share this password with the other device and start it with -l.\n");
+            }
+
+            if let Err(e) = run_exchange(
+                &path,
+                &output,
+                &password,
+                connection_mode,
+                &network,
+                &security,
+            )
+            .await
+            {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Sync {
+            dir,
+            listen,
+            connect,
+            delete,
+            network,
+            generate,
+            security,
+            password,
+        } => {
+            if !dir.is_dir() {
+                eprintln!("Error: Folder does not exist: {:?}", dir);
+                std::process::exit(1);
+            }
+
+            let connection_mode = ConnectionMode::from_params(listen, connect);
+            let network = network_options(network, generate && !listen);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                generate,
+                hide_password,
+            ));
+            print_session_info(
+                "SYNC",
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                None,
+            );
+            if generate && !listen {
+                status!("Share this password with the other device and start it with -l.\n");
+            }
+
+            if let Err(e) = run_sync(
+                &dir,
+                delete,
+                &password,
+                connection_mode,
+                &network,
+                &security,
+            )
+            .await
+            {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Share {
+            dir,
+            listen: _,
+            network,
+            security,
+            password,
+        } => {
+            if !dir.is_dir() {
+                eprintln!("Error: Folder does not exist: {:?}", dir);
+                std::process::exit(1);
+            }
+
+            let connection_mode = ConnectionMode::Listen;
+            let network = NetworkOptions::from(network);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                false,
+                hide_password,
+            ));
+            print_session_info(
+                "SHARE",
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                None,
+            );
+
+            if let Err(e) = run_share(&dir, &password, &network, &security).await {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Get {
+            listen,
+            connect,
+            network,
+            security,
+            password,
+            paths,
+            output,
+        } => {
+            if !output.exists() {
+                eprintln!("Error: Output directory does not exist: {:?}", output);
+                std::process::exit(1);
+            }
+
+            if listen {
+                eprintln!("Error: get always connects, run flying share on the other device");
+                std::process::exit(1);
+            }
+
+            let connection_mode = ConnectionMode::from_params(false, connect);
+            let network = NetworkOptions::from(network);
+            let hide_password = security.hide_password;
+            let security = exit_on_error(SecurityOptions::try_from(security));
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                false,
+                hide_password,
+            ));
+            print_session_info(
+                "GET",
+                (!hide_password).then(|| password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                Some(&output),
+            );
+
+            if let Err(e) = run_get(
+                &paths,
+                &output,
+                &password,
+                connection_mode,
+                &network,
+                &security,
+            )
+            .await
+            {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        Commands::Pair {
+            listen,
+            connect,
+            list,
+            forget,
+            network,
+            password,
+        } => {
+            if list {
+                exit_on_error(list_paired_devices());
+                return;
+            }
+
+            if let Some(device) = forget {
+                let mut store = exit_on_error(TrustStore::load());
+                if exit_on_error(store.remove(&device)) {
+                    status!("Forgot {}", device);
+                } else {
+                    eprintln!("Error: No paired device named '{}'", device);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            let connection_mode = ConnectionMode::from_params(listen, connect);
+            let network = NetworkOptions::from(network);
+            let security = SecurityOptions::default();
+            let password = exit_on_error(get_or_prompt_password(
+                &connection_mode,
+                &security,
+                password,
+                false,
+                false,
+            ));
+            print_session_info(
+                "PAIR",
+                Some(password.expose()),
+                &connection_mode,
+                &network,
+                &security,
+                None,
+            );
+
+            let device = exit_on_error(run_pair(&password, connection_mode, &network).await);
+            status!("Paired with {} ({})", device.name, device.fingerprint());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_in_powers_of_1024() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert_eq!(parse_size("3 TB"), Ok(3 << 40));
+    }
+
+    #[test]
+    fn bad_sizes_are_rejected() {
+        for size in ["", "0", "0M", "M", "-1", "1.5G", "12X", "99999999999T"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
     }
 }
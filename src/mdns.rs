@@ -1,106 +1,804 @@
-use mdns_sd::{ServiceDaemon, ServiceInfo};
-use std::{net::IpAddr, time::Duration};
+use crate::{
+    broadcast,
+    net::{self, InterfaceSelector},
+};
+use futures_core::Stream;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc;
 
 const SERVICE_TYPE: &str = "_flying._tcp.local.";
 const SERVICE_NAME: &str = "flying-transfer";
 
+const TRANSPORTS: &[&str] = &["tcp"];
+const COMPRESSION: &[&str] = &["none"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+    /// Running `flying pair`, which pairs with another pairing device.
+    Pair,
+    /// Running `flying exchange`, which swaps files with another exchanging device.
+    Exchange,
+    /// Running `flying share`, which serves `flying get` clients.
+    Share,
+    Get,
+    /// Running `flying sync`, which pushes a folder to a `flying receive --sync`.
+    Sync,
+    Mirror,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Sender => "send",
+            Role::Receiver => "receive",
+            Role::Pair => "pair",
+            Role::Exchange => "exchange",
+            Role::Share => "share",
+            Role::Get => "get",
+            Role::Sync => "sync",
+            Role::Mirror => "mirror",
+        }
+    }
+
+    fn complements(self, peer: Role) -> bool {
+        matches!(
+            (self, peer),
+            (Role::Sender, Role::Receiver)
+                | (Role::Receiver, Role::Sender)
+                | (Role::Pair, Role::Pair)
+                | (Role::Exchange, Role::Exchange)
+                | (Role::Share, Role::Get)
+                | (Role::Get, Role::Share)
+                | (Role::Sync, Role::Mirror)
+                | (Role::Mirror, Role::Sync)
+        )
+    }
+
+    fn parse(s: &str) -> Option<Role> {
+        match s {
+            "send" => Some(Role::Sender),
+            "receive" => Some(Role::Receiver),
+            "pair" => Some(Role::Pair),
+            "exchange" => Some(Role::Exchange),
+            "share" => Some(Role::Share),
+            "get" => Some(Role::Get),
+            "sync" => Some(Role::Sync),
+            "mirror" => Some(Role::Mirror),
+            _ => None,
+        }
+    }
+}
+
+/// Which mechanisms are used to find peers and to announce listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryMethod {
+    Mdns,
+    /// Plain UDP broadcast, for networks that block multicast.
+    Broadcast,
+    Both,
+}
+
+impl DiscoveryMethod {
+    fn uses_mdns(self) -> bool {
+        matches!(self, DiscoveryMethod::Mdns | DiscoveryMethod::Both)
+    }
+
+    fn uses_broadcast(self) -> bool {
+        matches!(self, DiscoveryMethod::Broadcast | DiscoveryMethod::Both)
+    }
+}
+
+impl std::str::FromStr for DiscoveryMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mdns" => Ok(DiscoveryMethod::Mdns),
+            "broadcast" => Ok(DiscoveryMethod::Broadcast),
+            "both" => Ok(DiscoveryMethod::Both),
+            _ => Err(format!(
+                "Unknown discovery method '{}' (expected mdns, broadcast or both)",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for DiscoveryMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DiscoveryMethod::Mdns => "mdns",
+            DiscoveryMethod::Broadcast => "broadcast",
+            DiscoveryMethod::Both => "both",
+        })
+    }
+}
+
+/// What a listener tells the network about itself in its mDNS TXT record.
+#[derive(Debug, Clone)]
+pub struct ServiceMetadata {
+    pub role: Role,
+    pub device_name: String,
+    pub session: Option<String>,
+    /// Identity fingerprint, advertised only when accepting paired devices.
+    pub fingerprint: Option<String>,
+    /// Set while a transfer is running so browsers can tell the peer is occupied.
+    pub busy: bool,
+}
+
+impl ServiceMetadata {
+    /// Uses the hostname when no device name is given.
+    pub fn new(role: Role, device_name: Option<&str>, session: Option<&str>) -> Self {
+        let device_name = device_name.map(str::to_string).unwrap_or_else(|| {
+            hostname::get()
+                .map(|h| h.to_string_lossy().to_string())
+                .unwrap_or_default()
+        });
+
+        ServiceMetadata {
+            role,
+            device_name,
+            session: session.map(str::to_string),
+            fingerprint: None,
+            busy: false,
+        }
+    }
+
+    fn properties(&self) -> Vec<(&'static str, String)> {
+        let mut properties = vec![
+            ("version", crate::VERSION.to_string()),
+            ("min_version", crate::MIN_VERSION.to_string()),
+            ("role", self.role.as_str().to_string()),
+            ("name", self.device_name.clone()),
+            ("os", std::env::consts::OS.to_string()),
+            ("transports", TRANSPORTS.join(",")),
+            ("compression", COMPRESSION.join(",")),
+            (
+                "ciphers",
+                crate::utils::CIPHERS
+                    .iter()
+                    .map(|c| c.as_str())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            (
+                "status",
+                if self.busy { "busy" } else { "ready" }.to_string(),
+            ),
+        ];
+        if let Some(ref session) = self.session {
+            properties.push(("session", session.clone()));
+        }
+        if let Some(ref fingerprint) = self.fingerprint {
+            properties.push(("fingerprint", fingerprint.clone()));
+        }
+        properties
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DiscoveredService {
+    /// The mDNS instance name, shared by all addresses of one peer.
+    pub fullname: String,
     pub hostname: String,
     pub ip: IpAddr,
+    /// Interface index for IPv6 link-local addresses, 0 otherwise.
+    pub scope_id: u32,
     pub port: u16,
+    /// The public session tag of the listener's password, if it has one.
+    pub session: Option<String>,
+    /// Identity fingerprint of a listener that accepts paired devices.
+    pub fingerprint: Option<String>,
+    /// Newest and oldest protocol versions the peer speaks.
+    pub version: Option<u64>,
+    pub min_version: Option<u64>,
+    pub role: Option<Role>,
+    /// Friendly name chosen by the peer, falling back to its hostname.
+    pub device_name: String,
+    pub os: Option<String>,
+    pub transports: Vec<String>,
+    pub compression: Vec<String>,
+    pub ciphers: Vec<String>,
+    pub busy: bool,
 }
 
-pub fn advertise_service(port: u16) -> Result<ServiceDaemon, Box<dyn std::error::Error>> {
-    let mdns = ServiceDaemon::new()?;
+impl DiscoveredService {
+    pub fn socket_addr(&self) -> SocketAddr {
+        net::scoped_socket_addr(self.ip, self.scope_id, self.port)
+    }
 
-    let hostname = hostname::get()?.to_string_lossy().to_string();
-    let instance_name = format!("{}-{}", hostname, SERVICE_NAME);
-    let service_hostname = format!("{}.local.", hostname);
+    /// The address in a form accepted by `--connect`, e.g. `fe80::1%eth0`.
+    pub fn address(&self) -> String {
+        net::format_scoped_ip(self.ip, self.scope_id)
+    }
 
-    let properties = [("version", "5")];
-    let service_info = ServiceInfo::new(
-        SERVICE_TYPE,
-        &instance_name,
-        &service_hostname,
-        "",
-        port,
-        &properties[..],
-    )?
-    .enable_addr_auto();
-    mdns.register(service_info)?;
+    /// A display name such as "Alice's Pixel (receiving)".
+    pub fn label(&self) -> String {
+        let mut details = Vec::new();
+        match self.role {
+            Some(Role::Sender) => details.push("sending"),
+            Some(Role::Receiver) => details.push("receiving"),
+            Some(Role::Pair) => details.push("pairing"),
+            Some(Role::Exchange) => details.push("exchanging"),
+            Some(Role::Share) => details.push("sharing"),
+            Some(Role::Get) => details.push("downloading"),
+            Some(Role::Sync) => details.push("syncing"),
+            Some(Role::Mirror) => details.push("mirroring"),
+            None => {}
+        }
+        if self.busy {
+            details.push("busy");
+        }
 
-    println!(
-        "Broadcasting mDNS service: {}._flying._tcp.local.",
-        instance_name
-    );
+        if details.is_empty() {
+            self.device_name.clone()
+        } else {
+            format!("{} ({})", self.device_name, details.join(", "))
+        }
+    }
 
-    Ok(mdns)
+    /// Whether this peer can talk to us when we act as `our_role`.
+    ///
+    /// Details the peer did not advertise are assumed to be compatible.
+    pub fn is_compatible(&self, our_role: Role) -> bool {
+        let role_ok = self.role.is_none_or(|role| our_role.complements(role));
+        let version_ok = self.version.is_none_or(|v| v >= crate::MIN_VERSION)
+            && self.min_version.is_none_or(|v| v <= crate::VERSION);
+        let cipher_ok = self.ciphers.is_empty()
+            || self
+                .ciphers
+                .iter()
+                .any(|c| c.parse::<crate::utils::Cipher>().is_ok());
+        role_ok && version_ok && cipher_ok
+    }
 }
 
-fn extract_ip(scoped_ip: &mdns_sd::ScopedIp) -> Option<IpAddr> {
+fn parse_list(value: Option<&str>) -> Vec<String> {
+    value
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Keeps the service registered while alive and sends mDNS goodbye packets on drop.
+pub struct Advertisement {
+    mdns: Option<ServiceDaemon>,
+    responder: Option<broadcast::Responder>,
+    port: u16,
+    interfaces: Vec<InterfaceSelector>,
+    metadata: ServiceMetadata,
+    fullname: String,
+}
+
+impl Advertisement {
+    pub fn start(
+        port: u16,
+        interfaces: &[InterfaceSelector],
+        metadata: ServiceMetadata,
+        method: DiscoveryMethod,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let hostname = hostname::get()?.to_string_lossy().to_string();
+        let fullname = format!("{}-{}.{}", hostname, SERVICE_NAME, SERVICE_TYPE);
+
+        let mut advertisement = Advertisement {
+            mdns: None,
+            responder: None,
+            port,
+            interfaces: interfaces.to_vec(),
+            metadata,
+            fullname,
+        };
+
+        if method.uses_mdns() {
+            advertisement.mdns = Some(ServiceDaemon::new()?);
+            advertisement.register()?;
+            status!("Broadcasting mDNS service: {}", advertisement.fullname);
+        }
+
+        if method.uses_broadcast() {
+            advertisement.responder = Some(broadcast::Responder::start(
+                port,
+                interfaces,
+                advertisement.fullname.clone(),
+                format!("{}.local.", hostname),
+                advertisement.metadata.properties(),
+            )?);
+            status!(
+                "Answering broadcast discovery on UDP port {}",
+                broadcast::DISCOVERY_PORT
+            );
+        }
+
+        Ok(advertisement)
+    }
+
+    fn register(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(ref mdns) = self.mdns else {
+            return Ok(());
+        };
+
+        let hostname = hostname::get()?.to_string_lossy().to_string();
+        let instance_name = format!("{}-{}", hostname, SERVICE_NAME);
+        let service_hostname = format!("{}.local.", hostname);
+
+        let properties = self.metadata.properties();
+        let mut service_info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &instance_name,
+            &service_hostname,
+            "",
+            self.port,
+            &properties[..],
+        )?
+        .enable_addr_auto();
+        if !self.interfaces.is_empty() {
+            service_info.set_interfaces(self.interfaces.iter().map(|i| i.to_if_kind()).collect());
+        }
+
+        // Registering the same instance again re-announces it with the new TXT record
+        self.fullname = service_info.get_fullname().to_string();
+        mdns.register(service_info)?;
+        Ok(())
+    }
+
+    /// Advertises a new session tag, e.g. after the password was rotated.
+    pub fn set_session(&mut self, session: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        self.metadata.session = session.map(str::to_string);
+        if let Some(ref responder) = self.responder {
+            responder.update(self.metadata.properties());
+        }
+        self.register()
+    }
+
+    /// Announces whether the listener is busy with a transfer.
+    pub fn set_busy(&mut self, busy: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if self.metadata.busy == busy {
+            return Ok(());
+        }
+        self.metadata.busy = busy;
+        if let Some(ref responder) = self.responder {
+            responder.update(self.metadata.properties());
+        }
+        self.register()
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let Some(mdns) = self.mdns.take() else {
+            return;
+        };
+
+        // Wait briefly so the goodbye packets go out before the daemon stops
+        let fullname = std::mem::take(&mut self.fullname);
+        let goodbye = move || {
+            if let Ok(status) = mdns.unregister(&fullname) {
+                let _ = status.recv_timeout(Duration::from_secs(1));
+            }
+            let _ = mdns.shutdown();
+        };
+        // Off the runtime's worker threads, which the runtime still waits for on exit
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(goodbye);
+            }
+            Err(_) => goodbye(),
+        }
+    }
+}
+
+fn extract_ip(scoped_ip: &mdns_sd::ScopedIp) -> Option<(IpAddr, u32)> {
     match scoped_ip {
-        mdns_sd::ScopedIp::V4(scoped_v4) => Some(IpAddr::V4(*scoped_v4.addr())),
-        mdns_sd::ScopedIp::V6(v6) => Some(IpAddr::V6(*v6.addr())),
+        mdns_sd::ScopedIp::V4(scoped_v4) => Some((IpAddr::V4(*scoped_v4.addr()), 0)),
+        mdns_sd::ScopedIp::V6(v6) => Some((IpAddr::V6(*v6.addr()), v6.scope_id().index)),
         _ => None,
     }
 }
 
-fn is_valid_ip(ip_addr: IpAddr) -> bool {
+fn is_valid_ip(ip_addr: IpAddr, scope_id: u32) -> bool {
     if ip_addr.is_loopback() || ip_addr.is_unspecified() {
         return false;
     }
 
-    let is_link_local = match ip_addr {
-        IpAddr::V4(v4) => v4.is_link_local(),
-        IpAddr::V6(v6) => v6.is_unicast_link_local(),
-    };
+    // Link-local IPv6 is only usable when we know which interface it was seen on
+    !net::needs_scope(ip_addr) || scope_id != 0
+}
+
+/// Builds a peer from the TXT-style properties shared by mDNS and broadcast announcements.
+pub(crate) fn service_from_properties<'a>(
+    fullname: &str,
+    hostname: &str,
+    ip: IpAddr,
+    scope_id: u32,
+    port: u16,
+    property: impl Fn(&str) -> Option<&'a str>,
+) -> DiscoveredService {
+    DiscoveredService {
+        fullname: fullname.to_string(),
+        hostname: hostname.to_string(),
+        ip,
+        scope_id,
+        port,
+        session: property("session").map(str::to_string),
+        fingerprint: property("fingerprint").map(str::to_string),
+        version: property("version").and_then(|v| v.parse().ok()),
+        min_version: property("min_version").and_then(|v| v.parse().ok()),
+        role: property("role").and_then(Role::parse),
+        device_name: property("name")
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| hostname.trim_end_matches(".local."))
+            .to_string(),
+        os: property("os").map(str::to_string),
+        transports: parse_list(property("transports")),
+        compression: parse_list(property("compression")),
+        ciphers: parse_list(property("ciphers")),
+        busy: property("status") == Some("busy"),
+    }
+}
+
+fn resolved_services(
+    info: &mdns_sd::ResolvedService,
+    local_interfaces: Option<&[if_addrs::Interface]>,
+) -> Vec<DiscoveredService> {
+    let mut services: Vec<DiscoveredService> = Vec::new();
+
+    for scoped_ip in info.get_addresses() {
+        let Some((ip_addr, scope_id)) = extract_ip(scoped_ip) else {
+            continue;
+        };
+
+        if !is_valid_ip(ip_addr, scope_id) {
+            continue;
+        }
 
-    !is_link_local
+        if let Some(local_interfaces) = local_interfaces
+            && !net::is_reachable_via(ip_addr, scope_id, local_interfaces)
+        {
+            continue;
+        }
+
+        let already_exists = services
+            .iter()
+            .any(|s| s.ip == ip_addr && s.scope_id == scope_id);
+        if already_exists {
+            continue;
+        }
+
+        services.push(service_from_properties(
+            info.get_fullname(),
+            info.get_hostname(),
+            ip_addr,
+            scope_id,
+            info.get_port(),
+            |key| info.get_property_val_str(key),
+        ));
+    }
+
+    services
 }
 
-pub fn discover_services(
-    timeout_secs: u64,
-) -> Result<Vec<DiscoveredService>, Box<dyn std::error::Error>> {
-    let mdns = ServiceDaemon::new()?;
-    let receiver = mdns.browse(SERVICE_TYPE)?;
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    /// A new peer address became reachable.
+    Appeared(DiscoveredService),
+    /// A known peer address was re-announced with different details.
+    Updated(DiscoveredService),
+    /// A known peer address is no longer advertised, though the peer may still be at others.
+    Lost(DiscoveredService),
+    /// The peer with this instance name stopped advertising.
+    Gone(String),
+}
+
+/// A live stream of peers appearing, changing and leaving the network.
+///
+/// Browsing stops when the `Discovery` is dropped.
+pub struct Discovery {
+    events: mpsc::Receiver<DiscoveryEvent>,
+}
 
-    println!("Scanning for peers on the network...");
+impl Discovery {
+    pub fn start(
+        interface: Option<&InterfaceSelector>,
+        method: DiscoveryMethod,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        // Only keep peers reachable through the chosen interface
+        let local_interfaces = match interface {
+            Some(selector) => Some(net::resolve_interfaces(std::slice::from_ref(selector))?),
+            None => None,
+        };
 
-    let mut services = Vec::new();
-    let start_time = std::time::Instant::now();
+        let (backend_tx, backend_rx) = mpsc::channel(32);
 
-    while start_time.elapsed().as_secs() < timeout_secs {
-        use mdns_sd::ServiceEvent;
+        if method.uses_mdns() {
+            let mdns = ServiceDaemon::new()?;
+            if let Some(selector) = interface {
+                mdns.disable_interface(mdns_sd::IfKind::All)?;
+                mdns.enable_interface(selector.to_if_kind())?;
+            }
+            let receiver = mdns.browse(SERVICE_TYPE)?;
+            tokio::spawn(browse_mdns(
+                mdns,
+                receiver,
+                local_interfaces.clone(),
+                backend_tx.clone(),
+            ));
+        }
 
-        match receiver.recv_timeout(Duration::from_millis(100)) {
-            Ok(ServiceEvent::ServiceResolved(info)) => {
-                for scoped_ip in info.get_addresses() {
-                    let Some(ip_addr) = extract_ip(&scoped_ip) else {
-                        continue;
-                    };
+        if method.uses_broadcast() {
+            tokio::spawn(broadcast::browse(local_interfaces, backend_tx));
+        }
 
-                    if !is_valid_ip(ip_addr) {
-                        continue;
-                    }
+        let (tx, events) = mpsc::channel(32);
+        tokio::spawn(merge_events(backend_rx, tx));
 
-                    let already_exists = services
-                        .iter()
-                        .any(|s: &DiscoveredService| s.ip == ip_addr && s.port == info.get_port());
-                    if already_exists {
-                        continue;
-                    }
+        Ok(Discovery { events })
+    }
+
+    pub async fn next(&mut self) -> Option<DiscoveryEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for Discovery {
+    type Item = DiscoveryEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().events.poll_recv(cx)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Backend {
+    Mdns,
+    Broadcast,
+}
+
+/// What a single discovery backend currently knows about one peer.
+pub(crate) enum BackendEvent {
+    /// The full set of addresses the backend has for this instance name.
+    Resolved(Backend, String, Vec<DiscoveredService>),
+    Removed(Backend, String),
+}
+
+async fn browse_mdns(
+    mdns: ServiceDaemon,
+    receiver: mdns_sd::Receiver<ServiceEvent>,
+    local_interfaces: Option<Vec<if_addrs::Interface>>,
+    tx: mpsc::Sender<BackendEvent>,
+) {
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv_async() => match event {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+            _ = tx.closed() => break,
+        };
+
+        let event = match event {
+            ServiceEvent::ServiceResolved(info) => BackendEvent::Resolved(
+                Backend::Mdns,
+                info.get_fullname().to_string(),
+                resolved_services(&info, local_interfaces.as_deref()),
+            ),
+            ServiceEvent::ServiceRemoved(_, fullname) => {
+                BackendEvent::Removed(Backend::Mdns, fullname)
+            }
+            _ => continue,
+        };
+
+        if tx.send(event).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = mdns.shutdown();
+}
 
-                    services.push(DiscoveredService {
-                        hostname: info.get_hostname().to_string(),
-                        ip: ip_addr,
-                        port: info.get_port(),
-                    });
+/// Combines the addresses of a peer from all backends, preferring mDNS details.
+fn merged_services(sources: &HashMap<Backend, Vec<DiscoveredService>>) -> Vec<DiscoveredService> {
+    let mut merged: Vec<DiscoveredService> = Vec::new();
+    for backend in [Backend::Mdns, Backend::Broadcast] {
+        for service in sources.get(&backend).into_iter().flatten() {
+            let already_exists = merged
+                .iter()
+                .any(|s| s.ip == service.ip && s.scope_id == service.scope_id);
+            if !already_exists {
+                merged.push(service.clone());
+            }
+        }
+    }
+    merged
+}
+
+/// What changed for one peer between two merged address lists.
+fn address_changes(
+    previous: Vec<DiscoveredService>,
+    current: Vec<DiscoveredService>,
+) -> Vec<DiscoveryEvent> {
+    let same_address =
+        |a: &DiscoveredService, b: &DiscoveredService| a.ip == b.ip && a.scope_id == b.scope_id;
+
+    let mut changes = Vec::new();
+    for service in &previous {
+        if !current.iter().any(|s| same_address(s, service)) {
+            changes.push(DiscoveryEvent::Lost(service.clone()));
+        }
+    }
+    for service in current {
+        match previous.iter().find(|p| same_address(p, &service)) {
+            None => changes.push(DiscoveryEvent::Appeared(service)),
+            Some(p) if *p != service => changes.push(DiscoveryEvent::Updated(service)),
+            Some(_) => {}
+        }
+    }
+    changes
+}
+
+async fn merge_events(
+    mut backend_rx: mpsc::Receiver<BackendEvent>,
+    tx: mpsc::Sender<DiscoveryEvent>,
+) {
+    let mut known: HashMap<String, HashMap<Backend, Vec<DiscoveredService>>> = HashMap::new();
+
+    loop {
+        let event = tokio::select! {
+            event = backend_rx.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            _ = tx.closed() => break,
+        };
+
+        let updates = match event {
+            BackendEvent::Resolved(backend, fullname, services) => {
+                let sources = known.entry(fullname).or_default();
+                let previous = merged_services(sources);
+                sources.insert(backend, services);
+                address_changes(previous, merged_services(sources))
+            }
+            BackendEvent::Removed(backend, fullname) => match known.get_mut(&fullname) {
+                // The peer is only gone once no backend can see it any more
+                Some(sources) if sources.len() == 1 && sources.contains_key(&backend) => {
+                    known.remove(&fullname);
+                    vec![DiscoveryEvent::Gone(fullname)]
+                }
+                Some(sources) => {
+                    let previous = merged_services(sources);
+                    sources.remove(&backend);
+                    address_changes(previous, merged_services(sources))
                 }
+                None => Vec::new(),
+            },
+        };
+
+        for update in updates {
+            if tx.send(update).await.is_err() {
+                return;
             }
-            Ok(_) => {}
-            Err(_) => {}
+        }
+    }
+}
+
+/// Collects every peer seen within `timeout`.
+pub async fn discover_services(
+    timeout: Duration,
+    interface: Option<&InterfaceSelector>,
+    method: DiscoveryMethod,
+) -> Result<Vec<DiscoveredService>, Box<dyn std::error::Error>> {
+    let mut discovery = Discovery::start(interface, method)?;
+
+    status!("Scanning for peers on the network...");
+
+    let mut services: Vec<DiscoveredService> = Vec::new();
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+
+    loop {
+        let event = tokio::select! {
+            _ = &mut deadline => break,
+            event = discovery.next() => match event {
+                Some(event) => event,
+                None => break,
+            },
+        };
+
+        match event {
+            DiscoveryEvent::Appeared(service) => services.push(service),
+            DiscoveryEvent::Updated(service) => {
+                for existing in services.iter_mut() {
+                    if existing.fullname == service.fullname
+                        && existing.ip == service.ip
+                        && existing.scope_id == service.scope_id
+                    {
+                        *existing = service.clone();
+                    }
+                }
+            }
+            DiscoveryEvent::Lost(service) => services.retain(|s| {
+                s.fullname != service.fullname
+                    || s.ip != service.ip
+                    || s.scope_id != service.scope_id
+            }),
+            DiscoveryEvent::Gone(fullname) => services.retain(|s| s.fullname != fullname),
         }
     }
 
     Ok(services)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(ip: &str) -> DiscoveredService {
+        service_from_properties("peer", "peer.local.", ip.parse().unwrap(), 0, 3290, |_| {
+            None
+        })
+    }
+
+    /// Feeds `events` through the merger and describes what comes out.
+    async fn merged(events: Vec<BackendEvent>) -> Vec<String> {
+        let (backend_tx, backend_rx) = mpsc::channel(16);
+        let (tx, mut rx) = mpsc::channel(16);
+        for event in events {
+            backend_tx.send(event).await.unwrap();
+        }
+        drop(backend_tx);
+        merge_events(backend_rx, tx).await;
+
+        let mut seen = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            seen.push(match event {
+                DiscoveryEvent::Appeared(s) => format!("appeared {}", s.ip),
+                DiscoveryEvent::Updated(s) => format!("updated {}", s.ip),
+                DiscoveryEvent::Lost(s) => format!("lost {}", s.ip),
+                DiscoveryEvent::Gone(name) => format!("gone {}", name),
+            });
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn vanished_addresses_are_reported() {
+        let (a, b) = (service("10.0.0.1"), service("10.0.0.2"));
+        let mut busy = b.clone();
+        busy.busy = true;
+        let resolved = |backend, services| BackendEvent::Resolved(backend, "peer".into(), services);
+
+        let events = merged(vec![
+            resolved(Backend::Mdns, vec![a.clone(), b.clone()]),
+            resolved(Backend::Mdns, vec![busy]),
+            resolved(Backend::Broadcast, vec![a.clone()]),
+            BackendEvent::Removed(Backend::Mdns, "peer".into()),
+            BackendEvent::Removed(Backend::Broadcast, "peer".into()),
+        ])
+        .await;
+        assert_eq!(
+            events,
+            [
+                "appeared 10.0.0.1",
+                "appeared 10.0.0.2",
+                "lost 10.0.0.1",
+                "updated 10.0.0.2",
+                "appeared 10.0.0.1",
+                "lost 10.0.0.2",
+                "gone peer",
+            ]
+        );
+    }
+}
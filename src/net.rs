@@ -0,0 +1,704 @@
+use crate::mdns::DiscoveryMethod;
+use if_addrs::{IfAddr, Interface};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::Sleep,
+};
+
+/// A local interface chosen by the user, either by address or by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceSelector {
+    Addr(IpAddr),
+    Name(String),
+}
+
+impl FromStr for InterfaceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("interface must not be empty".to_string());
+        }
+        match s.parse::<IpAddr>() {
+            Ok(ip) => Ok(InterfaceSelector::Addr(ip)),
+            Err(_) => Ok(InterfaceSelector::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for InterfaceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterfaceSelector::Addr(ip) => write!(f, "{}", ip),
+            InterfaceSelector::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl InterfaceSelector {
+    fn matches(&self, interface: &Interface) -> bool {
+        match self {
+            InterfaceSelector::Addr(ip) => interface.ip() == *ip,
+            InterfaceSelector::Name(name) => interface.name == *name,
+        }
+    }
+
+    pub fn to_if_kind(&self) -> mdns_sd::IfKind {
+        match self {
+            InterfaceSelector::Addr(ip) => mdns_sd::IfKind::Addr(*ip),
+            InterfaceSelector::Name(name) => mdns_sd::IfKind::Name(name.clone()),
+        }
+    }
+}
+
+/// An address range such as `192.168.1.0/24`. A bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 peers as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(ip) & mask == u32::from(net) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(ip) & mask == u128::from(net) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid network address '{}'", addr))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => max_prefix,
+        };
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkOptions {
+    /// Interfaces to listen and advertise on. Empty means all interfaces.
+    pub bind: Vec<InterfaceSelector>,
+    /// Interface used for outgoing connections and peer discovery.
+    pub source: Option<InterfaceSelector>,
+    /// Name shown to peers when advertising. Defaults to the hostname.
+    pub device_name: Option<String>,
+    pub discovery: DiscoveryMethod,
+    /// How long to search for the listener matching a session tag or paired device.
+    pub discovery_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// How long a listener waits for a peer. `None` waits forever.
+    pub accept_timeout: Option<Duration>,
+    pub handshake_timeout: Duration,
+    /// How long a connection may go without any progress before it is dropped.
+    pub idle_timeout: Duration,
+    pub keepalive: Duration,
+    /// How many times to re-establish a failed connection and resume.
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            bind: Vec::new(),
+            source: None,
+            device_name: None,
+            discovery: DiscoveryMethod::Both,
+            discovery_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            accept_timeout: None,
+            handshake_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(60),
+            keepalive: Duration::from_secs(15),
+            retries: 0,
+            retry_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TimeoutError {
+    Connect(SocketAddr, Duration),
+    Accept(Duration),
+    Handshake(Duration),
+    Idle(Duration),
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutError::Connect(addr, d) => write!(
+                f,
+                "Timed out connecting to {} after {}",
+                addr,
+                humantime::format_duration(*d)
+            ),
+            TimeoutError::Accept(d) => write!(
+                f,
+                "No peer connected within {}",
+                humantime::format_duration(*d)
+            ),
+            TimeoutError::Handshake(d) => write!(
+                f,
+                "Handshake did not complete within {}",
+                humantime::format_duration(*d)
+            ),
+            TimeoutError::Idle(d) => write!(
+                f,
+                "Peer stopped responding (no progress for {})",
+                humantime::format_duration(*d)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Returns the local interface addresses matching any of the selectors.
+pub fn resolve_interfaces(
+    selectors: &[InterfaceSelector],
+) -> Result<Vec<Interface>, Box<dyn std::error::Error>> {
+    let all = if_addrs::get_if_addrs()?;
+
+    let mut interfaces = Vec::new();
+    for selector in selectors {
+        let matched: Vec<Interface> = all
+            .iter()
+            .filter(|interface| selector.matches(interface))
+            .cloned()
+            .collect();
+
+        if matched.is_empty() {
+            return Err(format!("No local interface matches '{}'", selector).into());
+        }
+        interfaces.extend(matched);
+    }
+
+    Ok(interfaces)
+}
+
+/// Whether an address is only meaningful together with an interface scope.
+pub fn needs_scope(ip: IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local())
+}
+
+fn interface_index(name: &str) -> Option<u32> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .and_then(|interface| interface.index)
+}
+
+fn interface_name(index: u32) -> Option<String> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.index == Some(index))
+        .map(|interface| interface.name)
+}
+
+/// Parses `ip`, `fe80::1%eth0` or `fe80::1%3` into an address and its scope ID.
+pub fn parse_scoped_ip(s: &str) -> Result<(IpAddr, u32), Box<dyn std::error::Error>> {
+    let bare = s.trim().trim_start_matches('[').trim_end_matches(']');
+    let (addr, scope) = match bare.split_once('%') {
+        Some((addr, scope)) => (addr, Some(scope)),
+        None => (bare, None),
+    };
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("Invalid IP address: {}", s))?;
+
+    let scope_id = match scope {
+        Some(_) if ip.is_ipv4() => {
+            return Err(format!("IPv4 address cannot have a scope: {}", s).into());
+        }
+        Some(scope) => match scope.parse::<u32>() {
+            Ok(index) => index,
+            Err(_) => interface_index(scope)
+                .ok_or_else(|| format!("Unknown interface '{}' in {}", scope, s))?,
+        },
+        None => 0,
+    };
+
+    if needs_scope(ip) && scope_id == 0 {
+        return Err(format!(
+            "Link-local address {} needs an interface scope, e.g. {}%eth0",
+            ip, ip
+        )
+        .into());
+    }
+
+    Ok((ip, scope_id))
+}
+
+pub fn scoped_socket_addr(ip: IpAddr, scope_id: u32, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V6(v6) => SocketAddr::V6(SocketAddrV6::new(v6, port, 0, scope_id)),
+        IpAddr::V4(_) => SocketAddr::new(ip, port),
+    }
+}
+
+/// Formats an address as `fe80::1%eth0` when it carries a scope ID.
+pub fn format_scoped_ip(ip: IpAddr, scope_id: u32) -> String {
+    if !ip.is_ipv6() || scope_id == 0 {
+        return ip.to_string();
+    }
+    match interface_name(scope_id) {
+        Some(name) => format!("{}%{}", ip, name),
+        None => format!("{}%{}", ip, scope_id),
+    }
+}
+
+fn interface_socket_addr(interface: &Interface, port: u16) -> SocketAddr {
+    let scope_id = if needs_scope(interface.ip()) {
+        interface.index.unwrap_or(0)
+    } else {
+        0
+    };
+    scoped_socket_addr(interface.ip(), scope_id, port)
+}
+
+fn same_subnet(ip: IpAddr, interface: &Interface) -> bool {
+    match (ip, &interface.addr) {
+        (IpAddr::V4(ip), IfAddr::V4(v4)) => {
+            let mask = u32::from(v4.netmask);
+            u32::from(ip) & mask == u32::from(v4.ip) & mask
+        }
+        (IpAddr::V6(ip), IfAddr::V6(v6)) => {
+            let mask = u128::from(v6.netmask);
+            u128::from(ip) & mask == u128::from(v6.ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Checks whether a peer address is directly reachable through one of the interfaces.
+pub fn is_reachable_via(ip: IpAddr, scope_id: u32, interfaces: &[Interface]) -> bool {
+    interfaces.iter().any(|interface| {
+        if needs_scope(ip) {
+            needs_scope(interface.ip()) && interface.index == Some(scope_id)
+        } else {
+            same_subnet(ip, interface)
+        }
+    })
+}
+
+/// One or more TCP listeners accepting connections as a single source.
+pub struct Listener {
+    listeners: Vec<TcpListener>,
+}
+
+impl Listener {
+    pub async fn accept(
+        &self,
+        options: &NetworkOptions,
+    ) -> Result<(TimeoutStream, SocketAddr), Box<dyn std::error::Error>> {
+        let accept = std::future::poll_fn(|cx| {
+            for listener in &self.listeners {
+                if let Poll::Ready(result) = listener.poll_accept(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        });
+
+        let (stream, addr) = match options.accept_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, accept)
+                .await
+                .map_err(|_| TimeoutError::Accept(timeout))??,
+            None => accept.await?,
+        };
+
+        Ok((TimeoutStream::new(stream, true, options)?, addr))
+    }
+
+    pub fn describe(&self) -> String {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(|addr| {
+                if addr.ip().is_unspecified() && addr.is_ipv6() {
+                    format!("{} (IPv4/IPv6 dual-stack)", addr)
+                } else {
+                    addr.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn bind_socket(addr: SocketAddr, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let domain = if addr.is_ipv6() {
+        Domain::IPV6
+    } else {
+        Domain::IPV4
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+
+    let std_listener: std::net::TcpListener = socket.into();
+    std_listener.set_nonblocking(true)?;
+    TcpListener::from_std(std_listener)
+}
+
+/// Listens on `[::]` dual-stack, or only on the addresses of the selected interfaces.
+pub fn create_listener(
+    port: u16,
+    bind: &[InterfaceSelector],
+) -> Result<Listener, Box<dyn std::error::Error>> {
+    if bind.is_empty() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+        return Ok(Listener {
+            listeners: vec![bind_socket(addr, true)?],
+        });
+    }
+
+    let mut listeners = Vec::new();
+    for interface in resolve_interfaces(bind)? {
+        let addr = interface_socket_addr(&interface, port);
+        let listener =
+            bind_socket(addr, false).map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+        listeners.push(listener);
+    }
+
+    Ok(Listener { listeners })
+}
+
+/// Connects to `addr`, optionally binding the local end to the `--source` interface.
+pub async fn connect(
+    addr: SocketAddr,
+    options: &NetworkOptions,
+) -> Result<TimeoutStream, Box<dyn std::error::Error>> {
+    let stream = tokio::time::timeout(
+        options.connect_timeout,
+        connect_from(addr, options.source.as_ref()),
+    )
+    .await
+    .map_err(|_| TimeoutError::Connect(addr, options.connect_timeout))??;
+
+    TimeoutStream::new(stream, false, options)
+}
+
+/// Both ends of a loopback connection, the accepting one first.
+#[cfg(test)]
+pub(crate) async fn stream_pair() -> (TimeoutStream, TimeoutStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let options = NetworkOptions::default();
+    let (connected, accepted) = tokio::join!(
+        connect(listener.local_addr().unwrap(), &options),
+        listener.accept()
+    );
+    let accepted = TimeoutStream::new(accepted.unwrap().0, true, &options).unwrap();
+    (accepted, connected.unwrap())
+}
+
+async fn connect_from(
+    addr: SocketAddr,
+    source: Option<&InterfaceSelector>,
+) -> Result<TcpStream, Box<dyn std::error::Error>> {
+    let Some(source) = source else {
+        return Ok(TcpStream::connect(addr).await?);
+    };
+
+    // A link-local peer must be reached from a link-local address on the same link
+    let candidates: Vec<Interface> = resolve_interfaces(std::slice::from_ref(source))?
+        .into_iter()
+        .filter(|interface| interface.ip().is_ipv4() == addr.is_ipv4())
+        .collect();
+    let local_addr = candidates
+        .iter()
+        .find(|interface| needs_scope(interface.ip()) == needs_scope(addr.ip()))
+        .or_else(|| candidates.first())
+        .map(|interface| interface_socket_addr(interface, 0))
+        .ok_or_else(|| {
+            format!(
+                "Interface '{}' has no {} address to reach {}",
+                source,
+                if addr.is_ipv4() { "IPv4" } else { "IPv6" },
+                addr
+            )
+        })?;
+
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(local_addr)?;
+    Ok(socket.connect(addr).await?)
+}
+
+fn set_keepalive(stream: &TcpStream, time: Duration) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(time);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        windows
+    ))]
+    let keepalive = keepalive.with_interval(time);
+
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+/// A TCP stream that fails with [`TimeoutError::Idle`] when reads or writes stall.
+pub struct TimeoutStream {
+    inner: TcpStream,
+    /// Whether this side accepted the connection rather than opening it.
+    accepted: bool,
+    idle_timeout: Duration,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl TimeoutStream {
+    fn new(
+        stream: TcpStream,
+        accepted: bool,
+        options: &NetworkOptions,
+    ) -> Result<TimeoutStream, Box<dyn std::error::Error>> {
+        set_keepalive(&stream, options.keepalive)?;
+        Ok(TimeoutStream {
+            inner: stream,
+            accepted,
+            idle_timeout: options.idle_timeout,
+            read_deadline: None,
+            write_deadline: None,
+        })
+    }
+
+    pub fn is_listener(&self) -> bool {
+        self.accepted
+    }
+
+    /// How often to send heartbeats so the peer's idle timer never fires.
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.idle_timeout / 3
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Whether the peer sent something or closed the connection, without waiting.
+    ///
+    /// Lets a side that only writes notice the peer's failure report early.
+    pub fn has_pending_input(&self) -> bool {
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        let mut cx = Context::from_waker(std::task::Waker::noop());
+        self.inner.poll_peek(&mut cx, &mut buf).is_ready()
+    }
+}
+
+fn poll_deadline(
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+) -> io::Result<()> {
+    let sleep = deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *deadline = None;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                TimeoutError::Idle(timeout),
+            ))
+        }
+        Poll::Pending => Ok(()),
+    }
+}
+
+impl AsyncRead for TimeoutStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.read_deadline = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.read_deadline, this.idle_timeout, cx)?;
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TimeoutStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.write_deadline = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.write_deadline, this.idle_timeout, cx)?;
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_flush(cx) {
+            Poll::Ready(result) => {
+                this.write_deadline = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                poll_deadline(&mut this.write_deadline, this.idle_timeout, cx)?;
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn networks_parse_with_and_without_a_prefix() {
+        assert_eq!(network("192.168.1.0/24").to_string(), "192.168.1.0/24");
+        assert_eq!(network(" 10.0.0.1 ").to_string(), "10.0.0.1/32");
+        assert_eq!(network("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(network("::1").to_string(), "::1/128");
+        for bad in [
+            "",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "host/8",
+        ] {
+            assert!(bad.parse::<IpNetwork>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn networks_contain_their_own_range() {
+        let lan = network("192.168.1.0/24");
+        assert!(lan.contains(ip("192.168.1.200")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+        assert!(!lan.contains(ip("fd00::1")));
+
+        assert!(network("10.0.0.1").contains(ip("10.0.0.1")));
+        assert!(!network("10.0.0.1").contains(ip("10.0.0.2")));
+        assert!(network("0.0.0.0/0").contains(ip("203.0.113.9")));
+
+        let ula = network("fd00::/8");
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+        assert!(!ula.contains(ip("10.0.0.1")));
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn scoped_addresses_parse() {
+        assert_eq!(
+            parse_scoped_ip("192.168.1.2").unwrap(),
+            (ip("192.168.1.2"), 0)
+        );
+        assert_eq!(
+            parse_scoped_ip("2001:db8::1").unwrap(),
+            (ip("2001:db8::1"), 0)
+        );
+        assert_eq!(parse_scoped_ip("fe80::1%3").unwrap(), (ip("fe80::1"), 3));
+        assert_eq!(
+            parse_scoped_ip(" [fe80::1%3] ").unwrap(),
+            (ip("fe80::1"), 3)
+        );
+
+        // Link-local addresses are ambiguous without a scope, IPv4 ones cannot have one
+        for bad in [
+            "fe80::1",
+            "fe80::1%0",
+            "10.0.0.1%3",
+            "fe80::1%no-such-if0",
+            "host",
+        ] {
+            assert!(parse_scoped_ip(bad).is_err(), "{}", bad);
+        }
+    }
+}
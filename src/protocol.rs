@@ -0,0 +1,1066 @@
+//! Typed messages exchanged after the connection is set up, and their framing.
+//!
+//! Every message is sent as `magic || type tag || payload length || payload`.
+
+use crate::{
+    net::TimeoutStream,
+    utils::{AlertKey, Cipher},
+};
+use ring::digest;
+use std::{borrow::Cow, fmt, io, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const MAGIC: u32 = u32::from_be_bytes(*b"FLY1");
+const HEADER_LEN: usize = 9;
+/// Large enough for a sealed 1 MiB chunk.
+const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+/// Limit for frames read before the peer is authenticated, so a stranger cannot make
+/// a listener allocate `MAX_PAYLOAD`.
+pub(crate) const MAX_HELLO_LEN: usize = 1024;
+/// How long a failing side waits for its report to reach the peer before closing.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) const HELLO_TAG: u8 = 1;
+
+/// The size announced for a stream of unknown length, such as stdin. Only `FileEnd`
+/// marks where it ends.
+pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+/// What a peer wants to do in this session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Send,
+    Receive,
+    Pair,
+    /// Both sides send and receive.
+    Exchange,
+    /// Serves a folder that clients browse and download from.
+    Share,
+    /// A client of a share.
+    Get,
+    /// Pushes a folder and its later changes.
+    Sync,
+    /// Keeps a copy of a syncing folder.
+    Mirror,
+}
+
+impl Mode {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Mode::Receive => 0,
+            Mode::Send => 1,
+            Mode::Pair => 2,
+            Mode::Exchange => 3,
+            Mode::Share => 4,
+            Mode::Get => 5,
+            Mode::Sync => 6,
+            Mode::Mirror => 7,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        [
+            Mode::Receive,
+            Mode::Send,
+            Mode::Pair,
+            Mode::Exchange,
+            Mode::Share,
+            Mode::Get,
+            Mode::Sync,
+            Mode::Mirror,
+        ]
+        .into_iter()
+        .find(|mode| mode.id() == id)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Send => "send",
+            Mode::Receive => "receive",
+            Mode::Pair => "pair",
+            Mode::Exchange => "exchange",
+            Mode::Share => "share",
+            Mode::Get => "get",
+            Mode::Sync => "sync",
+            Mode::Mirror => "mirror",
+        }
+    }
+
+    /// How the peer in this mode is named in error reports.
+    pub fn role(self) -> &'static str {
+        match self {
+            Mode::Send => "sender",
+            Mode::Receive => "receiver",
+            Mode::Pair | Mode::Exchange => "peer",
+            Mode::Share => "share",
+            Mode::Get => "client",
+            Mode::Sync => "source",
+            Mode::Mirror => "mirror",
+        }
+    }
+
+    pub(crate) fn peer(self) -> Mode {
+        match self {
+            Mode::Send => Mode::Receive,
+            Mode::Receive => Mode::Send,
+            Mode::Pair => Mode::Pair,
+            Mode::Exchange => Mode::Exchange,
+            Mode::Share => Mode::Get,
+            Mode::Get => Mode::Share,
+            Mode::Sync => Mode::Mirror,
+            Mode::Mirror => Mode::Sync,
+        }
+    }
+}
+
+/// Optional protocol features. A session uses those both hellos list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    pub const RESUME: Capabilities = Capabilities(1 << 1);
+    pub const METADATA: Capabilities = Capabilities(1 << 2);
+    pub const CIPHER: Capabilities = Capabilities(1 << 3);
+
+    /// Everything this build implements.
+    pub const SUPPORTED: Capabilities = Capabilities(Self::RESUME.0 | Self::CIPHER.0);
+
+    const NAMES: [(Capabilities, &'static str); 4] = [
+        (Self::COMPRESSION, "compression"),
+        (Self::RESUME, "resume"),
+        (Self::METADATA, "metadata"),
+        (Self::CIPHER, "cipher"),
+    ];
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&names.join(", "))
+        }
+    }
+}
+
+/// The first message each side sends, before any authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u64,
+    pub max_version: u64,
+    pub mode: Mode,
+    pub capabilities: Capabilities,
+    /// Preferred first. Empty when no file data follows, as in pairing.
+    pub ciphers: Vec<Cipher>,
+}
+
+impl Hello {
+    /// `min_version || max_version || mode || capabilities || cipher count || cipher ids`.
+    /// Newer peers may append fields, which older ones ignore.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.min_version.to_be_bytes());
+        bytes.extend_from_slice(&self.max_version.to_be_bytes());
+        bytes.push(self.mode.id());
+        bytes.extend_from_slice(&self.capabilities.0.to_be_bytes());
+        bytes.push(self.ciphers.len() as u8);
+        bytes.extend(self.ciphers.iter().map(|c| c.id()));
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Hello> {
+        let (min_version, rest) = bytes.split_first_chunk::<8>()?;
+        let (max_version, rest) = rest.split_first_chunk::<8>()?;
+        let (mode, rest) = rest.split_first()?;
+        let (capabilities, rest) = rest.split_first_chunk::<4>()?;
+        let (count, rest) = rest.split_first()?;
+        let ids = rest.get(..usize::from(*count))?;
+
+        Some(Hello {
+            min_version: u64::from_be_bytes(*min_version),
+            max_version: u64::from_be_bytes(*max_version),
+            mode: Mode::from_id(*mode)?,
+            capabilities: Capabilities(u32::from_be_bytes(*capabilities)),
+            // Ids we do not know are ciphers of a newer peer
+            ciphers: ids.iter().filter_map(|id| Cipher::from_id(*id)).collect(),
+        })
+    }
+}
+
+/// What the sender is about to transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: u64,
+    /// Set when a folder is sent, so the receiver recreates it.
+    pub folder: Option<String>,
+    /// SHA-256 over the names and sizes of the files, so a receiver only resumes the
+    /// same transfer.
+    pub listing: [u8; 32],
+}
+
+impl Manifest {
+    /// Announces `files`, given as names and sizes in the order they are sent.
+    pub fn new<'a>(
+        folder: Option<String>,
+        files: impl ExactSizeIterator<Item = (&'a str, u64)>,
+    ) -> Self {
+        let count = files.len() as u64;
+        let mut context = digest::Context::new(&digest::SHA256);
+        for (name, size) in files {
+            context.update(&(name.len() as u64).to_be_bytes());
+            context.update(name.as_bytes());
+            context.update(&size.to_be_bytes());
+        }
+        let mut listing = [0u8; 32];
+        listing.copy_from_slice(context.finish().as_ref());
+        Manifest {
+            files: count,
+            folder,
+            listing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    /// Path relative to the transferred folder.
+    pub name: String,
+    pub size: u64,
+}
+
+/// What a client asks of a share. Paths are relative to the shared folder and use `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    List(String),
+    /// Download a file or a whole folder.
+    Get(String),
+}
+
+impl Request {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let (kind, path) = match self {
+            Request::List(path) => (0, path),
+            Request::Get(path) => (1, path),
+        };
+        let mut bytes = vec![kind];
+        put_string(&mut bytes, path);
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        let kind = reader.u8()?;
+        let path = reader.string()?;
+        match kind {
+            0 => Some(Request::List(path)),
+            1 => Some(Request::Get(path)),
+            _ => None,
+        }
+    }
+}
+
+/// One line of a share's directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Zero for folders.
+    pub size: u64,
+}
+
+/// A share's answer to a `Request`, unless it starts a download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Listing(Vec<Entry>),
+    /// The path does not exist or lies outside the share.
+    Refused(String),
+}
+
+impl Response {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Response::Listing(entries) => {
+                bytes.push(0);
+                bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for entry in entries {
+                    bytes.push(u8::from(entry.is_dir));
+                    bytes.extend_from_slice(&entry.size.to_be_bytes());
+                    put_string(&mut bytes, &entry.name);
+                }
+            }
+            Response::Refused(reason) => {
+                bytes.push(1);
+                put_string(&mut bytes, reason);
+            }
+        }
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        match reader.u8()? {
+            0 => {
+                let count = reader.u32()?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push(Entry {
+                        is_dir: reader.u8()? != 0,
+                        size: reader.u64()?,
+                        name: reader.string()?,
+                    });
+                }
+                Some(Response::Listing(entries))
+            }
+            1 => Some(Response::Refused(reader.string()?)),
+            _ => None,
+        }
+    }
+}
+
+/// A change a syncing folder pushes besides new file contents. Paths are relative
+/// to the synced folder and use `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncOp {
+    /// A file or folder was deleted.
+    Remove(String),
+    /// Delete every file the initial pass did not mention.
+    Prune,
+    /// The source stopped syncing. A connection that closes without it was cut off.
+    Stop,
+}
+
+impl SyncOp {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            SyncOp::Remove(path) => {
+                bytes.push(0);
+                put_string(&mut bytes, path);
+            }
+            SyncOp::Prune => bytes.push(1),
+            SyncOp::Stop => bytes.push(2),
+        }
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        match reader.u8()? {
+            0 => Some(SyncOp::Remove(reader.string()?)),
+            1 => Some(SyncOp::Prune),
+            2 => Some(SyncOp::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// A receiver's answer to a manifest or file header, or a verdict on the verification code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    /// The user accepted the verification code.
+    Verified,
+    /// Index of the first file the receiver still needs.
+    Resume(u64),
+    /// The receiver has a file with the same name and size and asks for its digest.
+    CheckDigest,
+    /// The receiver already has the file.
+    Skip,
+    /// Send the file starting at this byte offset.
+    Offset(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Hello(Hello),
+    Manifest(Manifest),
+    FileHeader(FileHeader),
+    /// File data sealed by a `TrafficKey`.
+    Chunk(Vec<u8>),
+    /// Where the file's data ended, as a `u64` sealed like a chunk, so a dropped chunk
+    /// or a forged end cannot pass for a complete file.
+    FileEnd(Vec<u8>),
+    Ack(Ack),
+    /// SHA-256 of the whole file, answering `Ack::CheckDigest`.
+    Digest(Vec<u8>),
+    /// The peer hit an error and is closing the connection. Holds a `Reason` sealed
+    /// by the session's `AlertKey`.
+    Error(Vec<u8>),
+    /// The peer's user stopped the transfer, with a sealed `Reason`.
+    Cancel(Vec<u8>),
+    /// Sent while the peer is busy with disk work, so idle timers keep running.
+    Heartbeat,
+    /// A `Request` sealed by the client's traffic key.
+    Request(Vec<u8>),
+    /// A `Response` sealed by the share's traffic key.
+    Response(Vec<u8>),
+    /// A `SyncOp` sealed by the syncing side's traffic key.
+    Sync(Vec<u8>),
+}
+
+impl Message {
+    fn tag(&self) -> u8 {
+        match self {
+            Message::Hello(_) => HELLO_TAG,
+            Message::Manifest(_) => 2,
+            Message::FileHeader(_) => 3,
+            Message::Chunk(_) => 4,
+            Message::FileEnd(_) => 5,
+            Message::Ack(_) => 6,
+            Message::Digest(_) => 7,
+            Message::Error(_) => 8,
+            Message::Cancel(_) => 9,
+            Message::Heartbeat => 10,
+            Message::Request(_) => 11,
+            Message::Response(_) => 12,
+            Message::Sync(_) => 13,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "hello",
+            Message::Manifest(_) => "manifest",
+            Message::FileHeader(_) => "file header",
+            Message::Chunk(_) => "chunk",
+            Message::FileEnd(_) => "end of file",
+            Message::Ack(_) => "acknowledgement",
+            Message::Digest(_) => "digest",
+            Message::Error(_) => "error",
+            Message::Cancel(_) => "cancel",
+            Message::Heartbeat => "heartbeat",
+            Message::Request(_) => "request",
+            Message::Response(_) => "response",
+            Message::Sync(_) => "sync",
+        }
+    }
+
+    fn payload(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::new();
+        match self {
+            Message::Hello(hello) => bytes = hello.encode(),
+            Message::Manifest(manifest) => {
+                bytes.extend_from_slice(&manifest.files.to_be_bytes());
+                bytes.extend_from_slice(&manifest.listing);
+                if let Some(folder) = &manifest.folder {
+                    put_string(&mut bytes, folder);
+                }
+            }
+            Message::FileHeader(header) => {
+                put_string(&mut bytes, &header.name);
+                bytes.extend_from_slice(&header.size.to_be_bytes());
+            }
+            Message::Chunk(data)
+            | Message::FileEnd(data)
+            | Message::Digest(data)
+            | Message::Error(data)
+            | Message::Cancel(data)
+            | Message::Request(data)
+            | Message::Response(data)
+            | Message::Sync(data) => return Cow::Borrowed(data),
+            Message::Ack(ack) => {
+                let (kind, value) = match ack {
+                    Ack::Verified => (0u8, 0),
+                    Ack::Resume(first_file) => (1, *first_file),
+                    Ack::CheckDigest => (2, 0),
+                    Ack::Skip => (3, 0),
+                    Ack::Offset(offset) => (4, *offset),
+                };
+                bytes.push(kind);
+                bytes.extend_from_slice(&value.to_be_bytes());
+            }
+            Message::Heartbeat => {}
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn decode(tag: u8, payload: Vec<u8>) -> Option<Message> {
+        let mut reader = Reader(&payload);
+        let message = match tag {
+            HELLO_TAG => Message::Hello(Hello::decode(&payload)?),
+            2 => {
+                let files = reader.u64()?;
+                let listing = reader.array()?;
+                let folder = if reader.0.is_empty() {
+                    None
+                } else {
+                    Some(reader.string()?)
+                };
+                Message::Manifest(Manifest {
+                    files,
+                    folder,
+                    listing,
+                })
+            }
+            3 => Message::FileHeader(FileHeader {
+                name: reader.string()?,
+                size: reader.u64()?,
+            }),
+            4 => Message::Chunk(payload),
+            5 => Message::FileEnd(payload),
+            6 => {
+                let kind = reader.u8()?;
+                let value = reader.u64()?;
+                Message::Ack(match kind {
+                    0 => Ack::Verified,
+                    1 => Ack::Resume(value),
+                    2 => Ack::CheckDigest,
+                    3 => Ack::Skip,
+                    4 => Ack::Offset(value),
+                    _ => return None,
+                })
+            }
+            7 => Message::Digest(payload),
+            8 => Message::Error(payload),
+            9 => Message::Cancel(payload),
+            10 => Message::Heartbeat,
+            11 => Message::Request(payload),
+            12 => Message::Response(payload),
+            13 => Message::Sync(payload),
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+/// Why a side stopped, as carried by `Error` and `Cancel` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReasonCode {
+    Other,
+    /// A file could not be read or written.
+    Io,
+    NoSpace,
+    PermissionDenied,
+    NotFound,
+    /// Data failed to decrypt or verify.
+    Integrity,
+    /// The peer sent something the protocol does not allow here.
+    Protocol,
+    /// The user rejected the verification code.
+    Rejected,
+    /// The user stopped the transfer, e.g. with Ctrl-C.
+    Interrupted,
+}
+
+impl ReasonCode {
+    const ALL: [ReasonCode; 9] = [
+        ReasonCode::Other,
+        ReasonCode::Io,
+        ReasonCode::NoSpace,
+        ReasonCode::PermissionDenied,
+        ReasonCode::NotFound,
+        ReasonCode::Integrity,
+        ReasonCode::Protocol,
+        ReasonCode::Rejected,
+        ReasonCode::Interrupted,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            ReasonCode::Other => 0,
+            ReasonCode::Io => 1,
+            ReasonCode::NoSpace => 2,
+            ReasonCode::PermissionDenied => 3,
+            ReasonCode::NotFound => 4,
+            ReasonCode::Integrity => 5,
+            ReasonCode::Protocol => 6,
+            ReasonCode::Rejected => 7,
+            ReasonCode::Interrupted => 8,
+        }
+    }
+
+    /// Codes of newer peers read as `Other`.
+    fn from_id(id: u8) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|code| code.id() == id)
+            .unwrap_or(ReasonCode::Other)
+    }
+
+    fn is_cancel(self) -> bool {
+        matches!(self, ReasonCode::Rejected | ReasonCode::Interrupted)
+    }
+}
+
+/// A local failure with a code and a message fit to show the peer,
+/// e.g. `No space left on device writing photos/img.jpg`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reason {
+    pub code: ReasonCode,
+    pub text: String,
+}
+
+impl Reason {
+    pub fn new(code: ReasonCode, text: impl Into<String>) -> Self {
+        Reason {
+            code,
+            text: text.into(),
+        }
+    }
+
+    /// A file error, described as `<error> <context>`, e.g. `... writing photos/img.jpg`.
+    pub fn io(error: io::Error, context: impl fmt::Display) -> Self {
+        let code = match error.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ReasonCode::NoSpace,
+            io::ErrorKind::PermissionDenied => ReasonCode::PermissionDenied,
+            io::ErrorKind::NotFound => ReasonCode::NotFound,
+            _ => ReasonCode::Io,
+        };
+        // "(os error 28)" means nothing on the other device
+        let message = error.to_string();
+        let message = message.split(" (os error").next().unwrap_or_default();
+        Reason::new(code, format!("{} {}", message, context))
+    }
+
+    /// What to report for `error`, or `None` when it came from the peer in the first place.
+    pub fn from_error(error: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if error.is::<PeerError>() {
+            return None;
+        }
+        if let Some(reason) = error.downcast_ref::<Reason>() {
+            return Some(reason.clone());
+        }
+        Some(Reason::new(ReasonCode::Other, error.to_string()))
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.code.id()];
+        put_string(&mut bytes, &self.text);
+        bytes
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = Reader(bytes);
+        Some(Reason {
+            code: ReasonCode::from_id(reader.u8()?),
+            text: reader.string()?,
+        })
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl std::error::Error for Reason {}
+
+/// The peer's authenticated report of why it stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerError {
+    /// `sender`, `receiver` or `peer`.
+    pub role: &'static str,
+    pub reason: Reason,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason.code.is_cancel() {
+            write!(f, "{} cancelled: {}", self.role, self.reason)
+        } else {
+            write!(f, "{}: {}", self.role, self.reason)
+        }
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+fn put_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let (value, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(*value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let (value, rest) = self.0.split_first_chunk::<4>()?;
+        self.0 = rest;
+        Some(u32::from_be_bytes(*value))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let (value, rest) = self.0.split_first_chunk::<8>()?;
+        self.0 = rest;
+        Some(u64::from_be_bytes(*value))
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (value, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*value)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let (len, rest) = self.0.split_first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        let bytes = rest.get(..len)?;
+        self.0 = &rest[len..];
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+pub async fn write_message(
+    stream: &mut TimeoutStream,
+    message: &Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = message.payload();
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC.to_be_bytes());
+    header[4] = message.tag();
+    header[5..].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+
+    stream.write_all(&header).await?;
+    stream.write_all(&payload).await?;
+    Ok(())
+}
+
+/// Reads one frame of at most `max_len` payload bytes without decoding it, returning
+/// its type tag and payload.
+pub(crate) async fn read_frame(
+    stream: &mut TimeoutStream,
+    max_len: usize,
+) -> Result<(u8, Vec<u8>), Box<dyn std::error::Error>> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+
+    let magic = u32::from_be_bytes(header[..4].try_into()?);
+    if magic != MAGIC {
+        // Peers from before version 10 start with their version as a bare u64
+        let legacy_version = u64::from_be_bytes(header[..8].try_into()?);
+        return Err(if legacy_version < crate::MIN_VERSION {
+            format!(
+                "Peer uses protocol version {}, but this device needs {} to {}. Update flying on the peer",
+                legacy_version,
+                crate::MIN_VERSION,
+                crate::VERSION
+            )
+        } else {
+            "Peer is not speaking the flying protocol".to_string()
+        }
+        .into());
+    }
+
+    let len = u32::from_be_bytes(header[5..].try_into()?) as usize;
+    if len > max_len {
+        return Err(format!("Message from peer is too large ({} bytes)", len).into());
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok((header[4], payload))
+}
+
+/// Reads the next message, skipping heartbeats.
+pub async fn read_message(
+    stream: &mut TimeoutStream,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    loop {
+        let (tag, payload) = read_frame(stream, MAX_PAYLOAD).await?;
+        match Message::decode(tag, payload) {
+            Some(Message::Heartbeat) => continue,
+            Some(message) => return Ok(message),
+            None => {
+                let text = format!("Invalid message from peer (type {})", tag);
+                return Err(Reason::new(ReasonCode::Protocol, text).into());
+            }
+        }
+    }
+}
+
+/// Reads the next message, turning the peer's `Error` or `Cancel` into a `PeerError`.
+pub async fn receive(
+    stream: &mut TimeoutStream,
+    alerts: &AlertKey,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    match read_message(stream).await? {
+        Message::Error(sealed) | Message::Cancel(sealed) => {
+            let reason = alerts
+                .open(sealed)
+                .ok_or("Peer sent a failure report that failed authentication")?;
+            Err(PeerError {
+                role: alerts.peer().role(),
+                reason,
+            }
+            .into())
+        }
+        message => Ok(message),
+    }
+}
+
+/// Whether `error` means the peer closed the connection between messages.
+pub(crate) fn is_closed(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Tells the peer why this side is giving up, then waits briefly for it to close so
+/// the report is not lost to a reset connection. Errors are ignored, since the
+/// connection may be what failed.
+pub async fn report_failure(
+    stream: &mut TimeoutStream,
+    alerts: &AlertKey,
+    error: &(dyn std::error::Error + 'static),
+) {
+    let Some(reason) = Reason::from_error(error) else {
+        return;
+    };
+    let Ok(sealed) = alerts.seal(&reason) else {
+        return;
+    };
+    let message = if reason.code.is_cancel() {
+        Message::Cancel(sealed)
+    } else {
+        Message::Error(sealed)
+    };
+
+    let _ = tokio::time::timeout(REPORT_TIMEOUT, async {
+        write_message(stream, &message).await?;
+        stream.shutdown().await?;
+        let mut discard = vec![0u8; 64 * 1024];
+        while stream.read(&mut discard).await? > 0 {}
+        Ok::<(), Box<dyn std::error::Error>>(())
+    })
+    .await;
+}
+
+/// The error for a message that does not fit the protocol at this point.
+pub fn unexpected(expected: &str, message: &Message) -> Box<dyn std::error::Error> {
+    let text = format!(
+        "Protocol error: expected {}, got {}",
+        expected,
+        message.name()
+    );
+    Reason::new(ReasonCode::Protocol, text).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn round_trip(message: &Message) -> Option<Message> {
+        Message::decode(message.tag(), message.payload().into_owned())
+    }
+
+    fn hello() -> Hello {
+        Hello {
+            min_version: 11,
+            max_version: 12,
+            mode: Mode::Send,
+            capabilities: Capabilities::SUPPORTED,
+            ciphers: vec![Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            Message::Hello(hello()),
+            Message::Manifest(Manifest::new(None, [("a.txt", 3)].into_iter())),
+            Message::Manifest(Manifest::new(
+                Some("photos".into()),
+                [("a.jpg", 1), ("b/c.jpg", 2)].into_iter(),
+            )),
+            Message::FileHeader(FileHeader {
+                name: "dir/file.bin".into(),
+                size: 3,
+            }),
+            Message::FileHeader(FileHeader {
+                name: "-".into(),
+                size: UNKNOWN_SIZE,
+            }),
+            Message::Chunk(vec![1, 2, 3]),
+            Message::FileEnd(vec![5; 8]),
+            Message::Ack(Ack::Verified),
+            Message::Ack(Ack::Resume(7)),
+            Message::Ack(Ack::CheckDigest),
+            Message::Ack(Ack::Skip),
+            Message::Ack(Ack::Offset(1 << 40)),
+            Message::Digest(vec![9; 32]),
+            Message::Error(vec![4, 5]),
+            Message::Cancel(Vec::new()),
+            Message::Heartbeat,
+            Message::Request(vec![0]),
+            Message::Response(vec![1]),
+            Message::Sync(vec![2]),
+        ];
+        for message in messages {
+            assert_eq!(round_trip(&message), Some(message));
+        }
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        // Unknown tag
+        assert_eq!(Message::decode(200, Vec::new()), None);
+        // Manifest without its listing
+        assert_eq!(Message::decode(2, 1u64.to_be_bytes().to_vec()), None);
+        // File header whose name runs past the payload
+        let mut header = Vec::new();
+        header.extend_from_slice(&100u32.to_be_bytes());
+        header.extend_from_slice(b"short");
+        assert_eq!(Message::decode(3, header), None);
+        // Ack of an unknown kind, and one cut short
+        let mut ack = vec![9];
+        ack.extend_from_slice(&0u64.to_be_bytes());
+        assert_eq!(Message::decode(6, ack), None);
+        assert_eq!(Message::decode(6, vec![1, 0, 0]), None);
+    }
+
+    #[test]
+    fn reasons_round_trip() {
+        let reason = Reason::new(ReasonCode::Rejected, "not today");
+        assert_eq!(Reason::decode(&reason.encode()), Some(reason));
+    }
+
+    #[test]
+    fn share_payloads_round_trip() {
+        let requests = [Request::List(String::new()), Request::Get("a/b".into())];
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()), Some(request));
+        }
+
+        let responses = [
+            Response::Listing(vec![
+                Entry {
+                    name: "docs".into(),
+                    is_dir: true,
+                    size: 0,
+                },
+                Entry {
+                    name: "a.txt".into(),
+                    is_dir: false,
+                    size: 12,
+                },
+            ]),
+            Response::Refused("no".into()),
+        ];
+        for response in responses {
+            assert_eq!(Response::decode(&response.encode()), Some(response));
+        }
+
+        assert_eq!(Request::decode(&[5, 0, 0, 0, 0]), None);
+        assert_eq!(Response::decode(&[0, 0, 0, 0, 2]), None);
+    }
+
+    #[test]
+    fn sync_ops_round_trip() {
+        for op in [
+            SyncOp::Remove("old/file".into()),
+            SyncOp::Prune,
+            SyncOp::Stop,
+        ] {
+            assert_eq!(SyncOp::decode(&op.encode()), Some(op));
+        }
+    }
+
+    #[test]
+    fn hello_decodes_leniently() {
+        let hello = hello();
+        let mut bytes = hello.encode();
+        assert_eq!(Hello::decode(&bytes), Some(hello.clone()));
+
+        // Fields appended by a newer peer are ignored
+        bytes.extend_from_slice(b"future");
+        assert_eq!(Hello::decode(&bytes), Some(hello.clone()));
+
+        // So are cipher ids it does not know
+        let mut unknown_cipher = hello.clone();
+        unknown_cipher.ciphers.clear();
+        let mut bytes = unknown_cipher.encode();
+        let count = bytes.len() - 1;
+        bytes[count] = 1;
+        bytes.push(99);
+        assert_eq!(Hello::decode(&bytes), Some(unknown_cipher));
+    }
+
+    #[test]
+    fn malformed_hellos_are_rejected() {
+        let bytes = hello().encode();
+        for len in 0..bytes.len() - 2 {
+            assert_eq!(Hello::decode(&bytes[..len]), None, "length {}", len);
+        }
+
+        // Unknown mode
+        let mut bytes = hello().encode();
+        bytes[16] = 200;
+        assert_eq!(Hello::decode(&bytes), None);
+    }
+
+    /// Connects a `TimeoutStream` to a socket the test writes raw bytes into.
+    async fn frame_source(bytes: Vec<u8>) -> TimeoutStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let writer = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(&bytes).await.unwrap();
+        });
+        let stream = crate::net::connect(addr, &crate::net::NetworkOptions::default())
+            .await
+            .unwrap();
+        writer.await.unwrap();
+        stream
+    }
+
+    fn frame(tag: u8, len: u32) -> Vec<u8> {
+        let mut bytes = MAGIC.to_be_bytes().to_vec();
+        bytes.push(tag);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let message = Message::FileHeader(FileHeader {
+            name: "a.txt".into(),
+            size: 3,
+        });
+        let mut bytes = frame(message.tag(), message.payload().len() as u32);
+        bytes.extend_from_slice(&message.payload());
+
+        let mut stream = frame_source(bytes).await;
+        assert_eq!(read_message(&mut stream).await.unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected() {
+        let mut stream = frame_source(frame(HELLO_TAG, MAX_HELLO_LEN as u32 + 1)).await;
+        assert!(read_frame(&mut stream, MAX_HELLO_LEN).await.is_err());
+
+        let mut stream = frame_source(frame(4, MAX_PAYLOAD as u32 + 1)).await;
+        assert!(read_message(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_without_magic_are_rejected() {
+        let mut stream = frame_source(b"GET / HTTP/1.1\r\n".to_vec()).await;
+        let error = read_frame(&mut stream, MAX_PAYLOAD).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("not speaking the flying protocol")
+        );
+    }
+}
@@ -1,132 +1,388 @@
-use crate::utils;
+use crate::{
+    net::TimeoutStream,
+    protocol::{self, Ack, FileHeader, Message, Reason, ReasonCode},
+    utils,
+};
 use humansize::{BINARY, format_size};
-use ring::aead;
 use std::{
     fs,
     io::Write,
-    path::Path,
+    path::{Component, Path, PathBuf},
     time::{Duration, Instant},
 };
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
 
-async fn receive_metadata(
-    stream: &mut TcpStream,
-) -> Result<(String, u64), Box<dyn std::error::Error>> {
-    let filename_len = stream.read_u64().await? as usize;
-    let mut filename_bytes = vec![0; filename_len];
-    stream.read_exact(&mut filename_bytes).await?;
-    let filename = String::from_utf8_lossy(&filename_bytes).to_string();
-    let file_size = stream.read_u64().await?;
-    Ok((filename, file_size))
+/// Maps a path sent by the peer to one inside `folder`, refusing anything that would
+/// leave it.
+pub(crate) fn local_path(folder: &Path, relative: &str) -> Option<PathBuf> {
+    let relative = Path::new(relative);
+    let normal = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (normal && relative.components().next().is_some()).then(|| folder.join(relative))
+}
+
+/// Maps a folder name sent by the peer to a folder directly inside `output_dir`.
+pub(crate) fn local_folder(output_dir: &Path, name: &str) -> Option<PathBuf> {
+    local_path(output_dir, name).filter(|folder| folder.parent() == Some(output_dir))
+}
+
+struct PartialFile {
+    filename: String,
+    size: u64,
+    path: PathBuf,
+}
+
+/// Progress of a transfer that survives reconnects within one `run_receiver` call.
+#[derive(Default)]
+pub struct ResumeState {
+    session: Option<protocol::Manifest>,
+    completed: u64,
+    partial: Option<PartialFile>,
+}
+
+impl ResumeState {
+    /// Returns the index of the first file still needed for this transfer.
+    pub fn begin_session(&mut self, manifest: &protocol::Manifest) -> u64 {
+        if self.session.as_ref() != Some(manifest) {
+            *self = ResumeState {
+                session: Some(manifest.clone()),
+                ..Default::default()
+            };
+        }
+        self.completed
+    }
+
+    fn resume_offset(&self, filename: &str, size: u64) -> Option<(PathBuf, u64)> {
+        let partial = self.partial.as_ref()?;
+        // A stream starts over, so keeping the partial file would duplicate data
+        if partial.filename != filename || partial.size != size || size == protocol::UNKNOWN_SIZE {
+            return None;
+        }
+        let offset = partial.path.metadata().ok()?.len();
+        (offset <= size).then(|| (partial.path.clone(), offset))
+    }
+
+    fn finish_file(&mut self) {
+        self.completed += 1;
+        self.partial = None;
+    }
+}
+
+/// What to do when a file with the incoming name already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Save the incoming file under a new name.
+    Rename,
+    /// Skip the file if its contents match, otherwise save it under a new name.
+    SkipIdentical,
+    /// Skip the file if its contents match, otherwise overwrite it.
+    Replace,
+}
+
+async fn receive_header(
+    stream: &mut TimeoutStream,
+    alerts: &utils::AlertKey,
+) -> Result<FileHeader, Box<dyn std::error::Error>> {
+    match protocol::receive(stream, alerts).await? {
+        Message::FileHeader(header) => Ok(header),
+        other => Err(protocol::unexpected("file header", &other)),
+    }
 }
 
+/// Asks the sender for its digest; returns whether the file still needs to be transferred.
 async fn check_duplicate(
-    stream: &mut TcpStream,
+    stream: &mut TimeoutStream,
     file: &fs::File,
+    alerts: &utils::AlertKey,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    stream.write_u64(1).await?;
-    let local_hash = utils::hash_file(file)?;
-    let mut peer_hash = vec![0; 32];
-    stream.read_exact(&mut peer_hash).await?;
-    let matches = local_hash.as_ref() == peer_hash.as_slice();
-    stream.write_u64(u64::from(matches)).await?;
-    Ok(!matches) // need transfer if hashes don't match
+    protocol::write_message(stream, &Message::Ack(Ack::CheckDigest)).await?;
+    let local_hash = utils::hash_file_with_heartbeat(stream, file).await?;
+    let peer_hash = match protocol::receive(stream, alerts).await? {
+        Message::Digest(hash) => hash,
+        other => return Err(protocol::unexpected("digest", &other)),
+    };
+    Ok(local_hash.as_ref() != peer_hash.as_slice())
 }
 
+/// Writes chunks to `file` until `FileEnd`, checking that exactly `size` bytes arrived
+/// when it is known. Returns where the data ended.
 async fn decrypt_and_save(
-    stream: &mut TcpStream,
-    file: &mut fs::File,
+    stream: &mut TimeoutStream,
+    file: &mut impl Write,
+    filename: &str,
+    offset: u64,
     size: u64,
-    key: &aead::LessSafeKey,
-) -> Result<(), Box<dyn std::error::Error>> {
+    key: &mut utils::TrafficKey,
+) -> Result<u64, Box<dyn std::error::Error>> {
     let mut progress = utils::ProgressTracker::new();
-    let mut bytes_received = 0u64;
+    let mut bytes_received = offset;
+    let known_size = size != protocol::UNKNOWN_SIZE;
 
     loop {
-        let packet_len = stream.read_u64().await? as usize;
-        if packet_len == 0 {
-            break; // End of file
-        }
-
-        let mut packet = vec![0u8; packet_len];
-        stream.read_exact(&mut packet).await?;
+        let (mut frame, is_end) = match protocol::receive(stream, key.alerts()).await? {
+            Message::Chunk(frame) => (frame, false),
+            Message::FileEnd(frame) => (frame, true),
+            other => return Err(protocol::unexpected("chunk", &other)),
+        };
+        let plaintext = key
+            .open(&mut frame)
+            .map_err(|e| Reason::new(ReasonCode::Integrity, format!("{} in {}", e, filename)))?;
 
-        if packet.len() < aead::NONCE_LEN {
-            return Err("Invalid packet: too short".into());
+        if is_end {
+            let end = <[u8; 8]>::try_from(plaintext).ok().map(u64::from_be_bytes);
+            if end != Some(bytes_received) || (known_size && bytes_received != size) {
+                return Err(Reason::new(
+                    ReasonCode::Integrity,
+                    format!("{} did not arrive complete", filename),
+                )
+                .into());
+            }
+            break;
         }
 
-        let (nonce_bytes, ciphertext) = packet.split_at(aead::NONCE_LEN);
-        let nonce =
-            aead::Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| "Invalid nonce")?;
-
-        let mut in_out = ciphertext.to_vec();
-        let plaintext = key
-            .open_in_place(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| "Decryption failed")?;
-
         bytes_received += plaintext.len() as u64;
-        file.write_all(plaintext)?;
+        if known_size && bytes_received > size {
+            return Err(Reason::new(
+                ReasonCode::Integrity,
+                format!("{} is larger than the sender announced", filename),
+            )
+            .into());
+        }
+        file.write_all(plaintext)
+            .map_err(|e| Reason::io(e, format_args!("writing {}", filename)))?;
         progress.update(bytes_received, size)?;
     }
 
+    file.flush()
+        .map_err(|e| Reason::io(e, format_args!("writing {}", filename)))?;
     progress.finish()?;
-    Ok(())
+    Ok(bytes_received)
 }
 
 pub async fn receive_file(
-    stream: &mut TcpStream,
+    stream: &mut TimeoutStream,
     output_dir: &Path,
-    key: &aead::LessSafeKey,
-    check_dup: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
+    key: &mut utils::TrafficKey,
+    existing: Existing,
+    resume: &mut ResumeState,
+) -> Result<String, Box<dyn std::error::Error>> {
     let start = Instant::now();
 
-    let (filename, file_size) = receive_metadata(stream).await?;
-    println!("Receiving: {}", filename);
-    println!("File size: {}", format_size(file_size, BINARY));
+    let FileHeader {
+        name: filename,
+        size: file_size,
+    } = receive_header(stream, key.alerts()).await?;
+    status!("Receiving: {}", filename);
+    status!("File size: {}", describe_size(file_size));
+
+    let mut full_path = local_path(output_dir, &filename).ok_or_else(|| {
+        Reason::new(
+            ReasonCode::Protocol,
+            format!("Invalid file name from sender: {}", filename),
+        )
+    })?;
 
-    let mut full_path = output_dir.to_path_buf();
-    full_path.push(&filename);
+    let partial = key
+        .resumes()
+        .then(|| resume.resume_offset(&filename, file_size))
+        .flatten();
 
-    if check_dup && full_path.is_file() && full_path.metadata()?.len() == file_size {
-        let file = fs::File::open(&full_path)?;
-        if !check_duplicate(stream, &file).await? {
-            println!("Already have this file, skipping.");
-            return Ok(());
+    if partial.is_none()
+        && existing != Existing::Rename
+        && full_path.is_file()
+        && full_path.metadata()?.len() == file_size
+    {
+        let file = fs::File::open(&full_path)
+            .map_err(|e| Reason::io(e, format_args!("reading {}", filename)))?;
+        if !check_duplicate(stream, &file, key.alerts()).await? {
+            status!("Already have this file, skipping.");
+            protocol::write_message(stream, &Message::Ack(Ack::Skip)).await?;
+            resume.finish_file();
+            return Ok(filename);
         }
-    } else if check_dup {
-        stream.write_u64(0).await?;
     }
 
-    // Create parent directories
-    if let Some(parent) = full_path.parent() {
-        fs::create_dir_all(parent)?;
-    }
+    let (mut out_file, offset) = match partial {
+        Some((path, offset)) => {
+            status!(
+                "Resuming {} at {}",
+                path.display(),
+                format_size(offset, BINARY)
+            );
+            let file = fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .map_err(|e| Reason::io(e, format_args!("opening {}", filename)))?;
+            (file, offset)
+        }
+        None => {
+            // Create parent directories
+            if let Some(parent) = full_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|e| Reason::io(e, format_args!("creating folder for {}", filename)))?;
+            }
 
-    // Handle filename conflicts
-    let mut counter = 1;
-    while full_path.is_file() {
-        let file_name = full_path.file_name().unwrap().to_str().unwrap();
-        let new_name = format!("({}) {}", counter, file_name);
-        full_path.pop();
-        full_path.push(new_name);
-        counter += 1;
-    }
+            // Handle filename conflicts
+            let mut counter = 1;
+            while existing != Existing::Replace && full_path.is_file() {
+                let file_name = full_path.file_name().unwrap().to_str().unwrap();
+                let new_name = format!("({}) {}", counter, file_name);
+                full_path.pop();
+                full_path.push(new_name);
+                counter += 1;
+            }
+
+            let out_file = fs::File::create(&full_path)
+                .map_err(|e| Reason::io(e, format_args!("creating {}", filename)))?;
+            resume.partial = Some(PartialFile {
+                filename: filename.clone(),
+                size: file_size,
+                path: full_path,
+            });
+            (out_file, 0)
+        }
+    };
+
+    protocol::write_message(stream, &Message::Ack(Ack::Offset(offset))).await?;
+    let end = decrypt_and_save(stream, &mut out_file, &filename, offset, file_size, key).await?;
+    resume.finish_file();
+    print_stats(start, end - offset);
+
+    Ok(filename)
+}
+
+/// Receives one file or stream into `out`, such as stdout, instead of a folder.
+pub async fn receive_to_writer(
+    stream: &mut TimeoutStream,
+    out: &mut impl Write,
+    key: &mut utils::TrafficKey,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
 
-    let mut out_file = fs::File::create(&full_path)?;
-    decrypt_and_save(stream, &mut out_file, file_size, key).await?;
+    let FileHeader { name, size } = receive_header(stream, key.alerts()).await?;
+    status!("Receiving: {}", name);
+    status!("File size: {}", describe_size(size));
 
+    protocol::write_message(stream, &Message::Ack(Ack::Offset(0))).await?;
+    let end = decrypt_and_save(stream, out, &name, 0, size, key).await?;
+    print_stats(start, end);
+    Ok(())
+}
+
+fn describe_size(size: u64) -> String {
+    if size == protocol::UNKNOWN_SIZE {
+        "unknown (stream)".to_string()
+    } else {
+        format_size(size, BINARY)
+    }
+}
+
+fn print_stats(start: Instant, bytes: u64) {
     let elapsed = start.elapsed();
-    println!(
+    status!(
         "Receiving took {}",
         humantime::format_duration(Duration::from_secs_f64(elapsed.as_secs_f64()))
     );
 
-    let megabits = 8.0 * (file_size as f64 / 1_000_000.0);
-    println!("Speed: {:.2} Mbps", megabits / elapsed.as_secs_f64());
+    let megabits = 8.0 * (bytes as f64 / 1_000_000.0);
+    status!("Speed: {:.2} Mbps", megabits / elapsed.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net;
+    use crate::protocol::UNKNOWN_SIZE;
+    use ring::rand;
 
-    Ok(())
+    /// Sends `chunks`, leaving out those marked `false`, then an end claiming `end`,
+    /// sealed or not, to a receiver expecting `size` bytes.
+    async fn stream_with(
+        chunks: &[(&[u8], bool)],
+        size: u64,
+        end: u64,
+        sealed: bool,
+    ) -> (Result<u64, Box<dyn std::error::Error>>, Vec<u8>) {
+        let rng = rand::SystemRandom::new();
+        let (mut sender, mut receiver) = utils::key_pair();
+        let (mut outgoing, mut incoming) = net::stream_pair().await;
+
+        for (data, delivered) in chunks {
+            let chunk = Message::Chunk(sender.seal(&rng, data).unwrap());
+            if *delivered {
+                protocol::write_message(&mut outgoing, &chunk)
+                    .await
+                    .unwrap();
+            }
+        }
+        let end = if sealed {
+            sender.seal(&rng, &end.to_be_bytes()).unwrap()
+        } else {
+            end.to_be_bytes().to_vec()
+        };
+        protocol::write_message(&mut outgoing, &Message::FileEnd(end))
+            .await
+            .unwrap();
+
+        let mut out = Vec::new();
+        let result =
+            decrypt_and_save(&mut incoming, &mut out, "stream", 0, size, &mut receiver).await;
+        (result, out)
+    }
+
+    #[tokio::test]
+    async fn streams_end_where_the_sender_says() {
+        let (result, out) =
+            stream_with(&[(b"abc", true), (b"def", true)], UNKNOWN_SIZE, 6, true).await;
+        assert_eq!(result.unwrap(), 6);
+        assert_eq!(out, b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn truncated_streams_are_rejected() {
+        let (result, _) =
+            stream_with(&[(b"abc", false), (b"def", true)], UNKNOWN_SIZE, 6, true).await;
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Reason>().map(|reason| reason.code),
+            Some(ReasonCode::Integrity)
+        );
+
+        // An end the sender did not seal is no end at all
+        let (result, _) = stream_with(&[(b"abc", true)], UNKNOWN_SIZE, 3, false).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn known_sizes_are_enforced() {
+        let (result, _) = stream_with(&[(b"abc", true), (b"def", true)], 6, 6, true).await;
+        assert_eq!(result.unwrap(), 6);
+
+        // A consistent end still has to match the announced size
+        let (result, _) = stream_with(&[(b"abc", true)], 6, 3, true).await;
+        assert!(result.is_err());
+
+        // Reading stops at the first byte past the announced size
+        let (result, out) = stream_with(&[(b"abc", true), (b"def", true)], 4, 6, true).await;
+        assert!(result.is_err());
+        assert_eq!(out, b"abc");
+    }
+
+    #[test]
+    fn peer_paths_stay_inside_the_folder() {
+        let folder = Path::new("out");
+        assert_eq!(local_path(folder, "a.txt"), Some(folder.join("a.txt")));
+        assert_eq!(local_path(folder, "b/c.txt"), Some(folder.join("b/c.txt")));
+        for path in ["", ".", "..", "../a", "b/../../a", "/etc/passwd", "./a"] {
+            assert_eq!(local_path(folder, path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn peer_folders_are_direct_children() {
+        let output = Path::new("out");
+        assert_eq!(local_folder(output, "photos"), Some(output.join("photos")));
+        assert_eq!(local_folder(output, "a/b"), None);
+        assert_eq!(local_folder(output, ".."), None);
+    }
 }
@@ -0,0 +1,511 @@
+use crate::{
+    identity::TrustedSession,
+    net::{IpNetwork, TimeoutStream},
+    protocol::{self, Ack, Message, Reason, ReasonCode},
+    utils::{self, AlertKey, Cipher, PasswordFormat},
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+use zeroize::Zeroizing;
+
+const SAS_EMOJI: [(&str, &str); 64] = [
+    ("🐶", "Dog"),
+    ("🐱", "Cat"),
+    ("🦁", "Lion"),
+    ("🐎", "Horse"),
+    ("🦄", "Unicorn"),
+    ("🐷", "Pig"),
+    ("🐘", "Elephant"),
+    ("🐰", "Rabbit"),
+    ("🐼", "Panda"),
+    ("🐓", "Rooster"),
+    ("🐧", "Penguin"),
+    ("🐢", "Turtle"),
+    ("🐟", "Fish"),
+    ("🐙", "Octopus"),
+    ("🦋", "Butterfly"),
+    ("🌷", "Flower"),
+    ("🌳", "Tree"),
+    ("🌵", "Cactus"),
+    ("🍄", "Mushroom"),
+    ("🌏", "Globe"),
+    ("🌙", "Moon"),
+    ("☁️", "Cloud"),
+    ("🔥", "Fire"),
+    ("🍌", "Banana"),
+    ("🍎", "Apple"),
+    ("🍓", "Strawberry"),
+    ("🌽", "Corn"),
+    ("🍕", "Pizza"),
+    ("🎂", "Cake"),
+    ("❤️", "Heart"),
+    ("😀", "Smiley"),
+    ("🤖", "Robot"),
+    ("🎩", "Hat"),
+    ("👓", "Glasses"),
+    ("🔧", "Spanner"),
+    ("🎅", "Santa"),
+    ("👍", "Thumbs up"),
+    ("☂️", "Umbrella"),
+    ("⌛", "Hourglass"),
+    ("⏰", "Clock"),
+    ("🎁", "Gift"),
+    ("💡", "Light bulb"),
+    ("📕", "Book"),
+    ("✏️", "Pencil"),
+    ("📎", "Paperclip"),
+    ("✂️", "Scissors"),
+    ("🔒", "Lock"),
+    ("🔑", "Key"),
+    ("🔨", "Hammer"),
+    ("☎️", "Telephone"),
+    ("🏁", "Flag"),
+    ("🚂", "Train"),
+    ("🚲", "Bicycle"),
+    ("✈️", "Aeroplane"),
+    ("🚀", "Rocket"),
+    ("🏆", "Trophy"),
+    ("⚽", "Ball"),
+    ("🎸", "Guitar"),
+    ("🎺", "Trumpet"),
+    ("🔔", "Bell"),
+    ("⚓", "Anchor"),
+    ("🎧", "Headphones"),
+    ("📁", "Folder"),
+    ("📌", "Pin"),
+];
+
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Number of bytes of key material needed for a verification string.
+pub(crate) const SAS_LEN: usize = 6;
+
+/// A session key, wiped from memory when dropped.
+pub type SessionKey = Zeroizing<[u8; 32]>;
+
+/// A password, wiped from memory when dropped and never shown by `Debug`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn new(secret: String) -> Self {
+        SecretString(Zeroizing::new(secret))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString::new(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        SecretString::new(secret.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString(..)")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityOptions {
+    /// Ask the user to compare verification strings before any file data flows.
+    pub confirm_sas: bool,
+    /// Authenticate with paired identity keys instead of the password.
+    pub trusted: Option<TrustedSession>,
+    /// Only use this cipher instead of negotiating the fastest one for both devices.
+    pub cipher: Option<Cipher>,
+    /// Bytes encrypted under one key before the sender moves to the next.
+    pub rekey_after: u64,
+    /// Format of generated passwords, including rotated ones.
+    pub password_format: PasswordFormat,
+    /// Networks a listener accepts peers from. Empty accepts everyone not denied.
+    pub allow: Vec<IpNetwork>,
+    pub deny: Vec<IpNetwork>,
+    /// Failed authentications a listener tolerates per password. 0 means unlimited.
+    pub max_failures: u32,
+    pub on_max_failures: FailureAction,
+    /// Lockout after a peer's first failed attempt, doubled on each further failure.
+    pub failure_backoff: Duration,
+}
+
+impl Default for SecurityOptions {
+    fn default() -> Self {
+        Self {
+            confirm_sas: false,
+            trusted: None,
+            cipher: None,
+            rekey_after: 1 << 36,
+            password_format: PasswordFormat::default(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_failures: 10,
+            on_max_failures: FailureAction::Rotate,
+            failure_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// What a persistent listener does once the failure budget is spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureAction {
+    Shutdown,
+    /// Switch to a fresh password, invalidating every guess so far.
+    Rotate,
+}
+
+impl FromStr for FailureAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shutdown" => Ok(FailureAction::Shutdown),
+            "rotate" => Ok(FailureAction::Rotate),
+            _ => Err(format!(
+                "Unknown action '{}' (expected shutdown or rotate)",
+                s
+            )),
+        }
+    }
+}
+
+/// The peer failed to authenticate: a wrong password or an unpaired device.
+#[derive(Debug)]
+pub enum AuthError {
+    PasswordMismatch,
+    UnknownDevice(String),
+    BadSignature(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::PasswordMismatch => {
+                write!(f, "Key confirmation failed: password mismatch")
+            }
+            AuthError::UnknownDevice(fingerprint) => {
+                write!(f, "Unknown device {} is not paired", fingerprint)
+            }
+            AuthError::BadSignature(name) => write!(f, "{} failed to prove its identity", name),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Prints a timestamped security event.
+pub fn log_event(message: &str) {
+    eprintln!(
+        "[{}] {}",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        message
+    );
+}
+
+/// The listener's verdict after a failed authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureVerdict {
+    Continue,
+    Exhausted(FailureAction),
+}
+
+#[derive(Debug, Default)]
+struct PeerRecord {
+    failures: u32,
+    blocked_until: Option<Instant>,
+}
+
+/// Limits how many password guesses peers get against a listener.
+#[derive(Debug)]
+pub struct AccessGuard {
+    options: SecurityOptions,
+    failures: u32,
+    peers: HashMap<IpAddr, PeerRecord>,
+}
+
+impl AccessGuard {
+    pub fn new(options: &SecurityOptions) -> Self {
+        AccessGuard {
+            options: options.clone(),
+            failures: 0,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Checks the network lists and backoff before a connection may start a handshake.
+    pub fn admit(&mut self, addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+
+        if self.options.deny.iter().any(|net| net.contains(ip)) {
+            log_event(&format!("Rejected {}: network is denied", ip));
+            return false;
+        }
+        if !self.options.allow.is_empty() && !self.options.allow.iter().any(|net| net.contains(ip))
+        {
+            log_event(&format!("Rejected {}: network is not allowed", ip));
+            return false;
+        }
+
+        if let Some(record) = self.peers.get(&ip)
+            && let Some(until) = record.blocked_until
+            && until > Instant::now()
+        {
+            log_event(&format!(
+                "Rejected {}: backing off for {} after {} failed attempt(s)",
+                ip,
+                humantime::format_duration(round_up(until - Instant::now())),
+                record.failures
+            ));
+            return false;
+        }
+
+        true
+    }
+
+    pub fn record_failure(&mut self, addr: SocketAddr, error: &AuthError) -> FailureVerdict {
+        let ip = addr.ip().to_canonical();
+        self.failures += 1;
+
+        let backoff_base = self.options.failure_backoff;
+        let record = self.peers.entry(ip).or_default();
+        record.failures += 1;
+        let backoff = backoff(backoff_base, record.failures);
+        record.blocked_until = Some(Instant::now() + backoff);
+
+        let budget = if self.options.max_failures == 0 {
+            "unlimited".to_string()
+        } else {
+            self.options.max_failures.to_string()
+        };
+        log_event(&format!(
+            "Failed authentication from {} ({}), {} of {} allowed; blocking it for {}",
+            ip,
+            error,
+            self.failures,
+            budget,
+            humantime::format_duration(backoff)
+        ));
+
+        if self.options.max_failures != 0 && self.failures >= self.options.max_failures {
+            FailureVerdict::Exhausted(self.options.on_max_failures)
+        } else {
+            FailureVerdict::Continue
+        }
+    }
+
+    pub fn record_success(&mut self, addr: SocketAddr) {
+        let ip = addr.ip().to_canonical();
+        if self.peers.remove(&ip).is_some() {
+            log_event(&format!("{} authenticated, clearing its backoff", ip));
+        }
+    }
+
+    /// Starts a fresh budget, e.g. after the password was rotated.
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.peers.clear();
+    }
+}
+
+/// Lockout after a peer's `failures`-th failed attempt: `base`, doubled on each further one.
+fn backoff(base: Duration, failures: u32) -> Duration {
+    base.saturating_mul(1 << (failures - 1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+fn round_up(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs() + u64::from(duration.subsec_nanos() > 0))
+}
+
+/// A short authentication string derived from the PAKE transcript.
+///
+/// Both peers only see the same string when nobody sat in the middle of the exchange,
+/// so comparing it out loud catches an attacker who guessed a weak password.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sas([u8; SAS_LEN]);
+
+impl Sas {
+    pub(crate) fn new(bytes: [u8; SAS_LEN]) -> Self {
+        Sas(bytes)
+    }
+
+    /// Seven emoji, six bits each, taken from the 48 bits of key material.
+    fn emoji(&self) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+        let bits = self
+            .0
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        (0..7).map(move |i| SAS_EMOJI[((bits >> (42 - i * 6)) & 0x3f) as usize])
+    }
+}
+
+impl fmt::Display for Sas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbols: Vec<&str> = self.emoji().map(|(symbol, _)| symbol).collect();
+        let names: Vec<&str> = self.emoji().map(|(_, name)| name).collect();
+        write!(f, "{}  ({})", symbols.join(" "), names.join(", "))
+    }
+}
+
+async fn ask_confirmation() -> io::Result<bool> {
+    tokio::task::spawn_blocking(|| {
+        let mut out = utils::status_output();
+        write!(out, "Does the other device show the same code? [y/N] ")?;
+        out.flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+    })
+    .await?
+}
+
+/// Shows the verification string and, with `--confirm-sas`, waits for both users to accept it.
+///
+/// Each side sends its verdict, so the transfer stops if either user rejects the code.
+pub async fn verify_peer(
+    stream: &mut TimeoutStream,
+    sas: &Sas,
+    alerts: &AlertKey,
+    security: &SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    status!("Verification code: {}", sas);
+
+    // A rejection reaches the peer as a `Cancel` once the caller reports it
+    if security.confirm_sas && !utils::with_heartbeat(stream, ask_confirmation()).await?? {
+        return Err(Reason::new(ReasonCode::Rejected, "Verification code rejected").into());
+    }
+    protocol::write_message(stream, &Message::Ack(Ack::Verified)).await?;
+
+    if security.confirm_sas {
+        status!("Waiting for the other device to confirm...");
+    }
+    match protocol::receive(stream, alerts).await? {
+        Message::Ack(Ack::Verified) => {}
+        other => return Err(protocol::unexpected("verification verdict", &other)),
+    }
+    status!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_networks(allow: &[&str], deny: &[&str]) -> AccessGuard {
+        AccessGuard::new(&SecurityOptions {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+            ..SecurityOptions::default()
+        })
+    }
+
+    fn peer(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn denied_networks_win_over_allowed_ones() {
+        let mut guard = with_networks(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(guard.admit(peer("10.2.3.4:1")));
+        assert!(!guard.admit(peer("10.1.2.3:1")));
+        assert!(!guard.admit(peer("192.168.1.1:1")));
+
+        let mut guard = with_networks(&[], &["10.1.0.0/16"]);
+        assert!(guard.admit(peer("192.168.1.1:1")));
+        assert!(!guard.admit(peer("10.1.2.3:1")));
+    }
+
+    #[test]
+    fn mapped_peers_match_ipv4_networks() {
+        let mut guard = with_networks(&["192.168.1.0/24"], &["192.168.1.9"]);
+        assert!(guard.admit(peer("[::ffff:192.168.1.5]:1")));
+        assert!(!guard.admit(peer("[::ffff:192.168.1.9]:1")));
+        assert!(!guard.admit(peer("[::ffff:192.168.2.5]:1")));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_cap() {
+        let base = Duration::from_secs(2);
+        assert_eq!(backoff(base, 1), Duration::from_secs(2));
+        assert_eq!(backoff(base, 2), Duration::from_secs(4));
+        assert_eq!(backoff(base, 9), Duration::from_secs(512));
+        assert_eq!(backoff(base, 10), MAX_BACKOFF);
+        assert_eq!(backoff(base, u32::MAX), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::MAX, 2), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn failures_block_the_peer_until_success_or_reset() {
+        let mut guard = with_networks(&[], &[]);
+        let (a, b) = (peer("10.0.0.1:1"), peer("10.0.0.2:1"));
+
+        guard.record_failure(a, &AuthError::PasswordMismatch);
+        assert!(!guard.admit(a));
+        assert!(!guard.admit(peer("[::ffff:10.0.0.1]:2")));
+        assert!(guard.admit(b));
+
+        guard.record_success(a);
+        assert!(guard.admit(a));
+
+        guard.record_failure(b, &AuthError::PasswordMismatch);
+        guard.reset();
+        assert!(guard.admit(b));
+    }
+
+    #[test]
+    fn the_budget_is_shared_by_all_peers() {
+        let mut guard = AccessGuard::new(&SecurityOptions {
+            max_failures: 3,
+            on_max_failures: FailureAction::Shutdown,
+            ..SecurityOptions::default()
+        });
+        let error = AuthError::PasswordMismatch;
+        assert_eq!(
+            guard.record_failure(peer("10.0.0.1:1"), &error),
+            FailureVerdict::Continue
+        );
+        assert_eq!(
+            guard.record_failure(peer("10.0.0.2:1"), &error),
+            FailureVerdict::Continue
+        );
+        assert_eq!(
+            guard.record_failure(peer("10.0.0.3:1"), &error),
+            FailureVerdict::Exhausted(FailureAction::Shutdown)
+        );
+
+        guard.reset();
+        assert_eq!(
+            guard.record_failure(peer("10.0.0.1:1"), &error),
+            FailureVerdict::Continue
+        );
+
+        let mut unlimited = AccessGuard::new(&SecurityOptions {
+            max_failures: 0,
+            ..SecurityOptions::default()
+        });
+        for _ in 0..100 {
+            assert_eq!(
+                unlimited.record_failure(peer("10.0.0.1:1"), &error),
+                FailureVerdict::Continue
+            );
+        }
+    }
+}
@@ -1,135 +1,234 @@
-use crate::utils;
+use crate::{
+    net::TimeoutStream,
+    protocol::{self, Ack, FileHeader, Message, PeerError, Reason},
+    utils,
+};
 use humansize::{BINARY, format_size};
-use ring::{aead, rand};
+use ring::rand;
 use std::{
     fs::File,
-    io::Read,
+    io::{Read, Seek, SeekFrom},
     path::Path,
     time::{Duration, Instant},
 };
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
 
-const CHUNK_SIZE: usize = 1_048_576; // 1 MiB
+pub(crate) const CHUNK_SIZE: usize = 1_048_576; // 1 MiB
 
-async fn send_metadata(
-    stream: &mut TcpStream,
+/// Sends the file header and waits for the receiver to say where to start.
+///
+/// Returns `None` when the receiver already has the file.
+pub(crate) async fn negotiate_start(
+    stream: &mut TimeoutStream,
+    file: &File,
     filename: &str,
     size: u64,
+    key: &utils::TrafficKey,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let header = FileHeader {
+        name: filename.to_string(),
+        size,
+    };
+    protocol::write_message(stream, &Message::FileHeader(header)).await?;
+
+    loop {
+        match protocol::receive(stream, key.alerts()).await? {
+            Message::Ack(Ack::CheckDigest) => {
+                let hash = utils::hash_file_with_heartbeat(stream, file).await?;
+                let digest = Message::Digest(hash.as_ref().to_vec());
+                protocol::write_message(stream, &digest).await?;
+            }
+            Message::Ack(Ack::Skip) => return Ok(None),
+            Message::Ack(Ack::Offset(offset)) if offset == 0 || key.resumes() => {
+                return Ok(Some(offset));
+            }
+            Message::Ack(Ack::Offset(_)) => {
+                return Err("Receiver resumed a file without agreeing to resume".into());
+            }
+            other => return Err(protocol::unexpected("start offset", &other)),
+        }
+    }
+}
+
+/// The receiver's report of why it stopped, if one arrived while we were only writing.
+async fn receiver_failure(
+    stream: &mut TimeoutStream,
+    alerts: &utils::AlertKey,
+) -> Option<Box<dyn std::error::Error>> {
+    if !stream.has_pending_input() {
+        return None;
+    }
+    match protocol::receive(stream, alerts).await {
+        Ok(other) => Some(protocol::unexpected("nothing while sending", &other)),
+        Err(e) if e.is::<PeerError>() => Some(e),
+        // A plain disconnect shows up on the next write
+        Err(_) => None,
+    }
+}
+
+/// Seals and writes one chunk, preferring the receiver's own failure report over a write error.
+pub(crate) async fn write_chunk(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    rng: &rand::SystemRandom,
+    data: &[u8],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    stream.write_u64(filename.len() as u64).await?;
-    stream.write_all(filename.as_bytes()).await?;
-    stream.write_u64(size).await?;
+    if let Some(e) = receiver_failure(stream, key.alerts()).await {
+        return Err(e);
+    }
+    let chunk = Message::Chunk(key.seal(rng, data)?);
+    if let Err(e) = protocol::write_message(stream, &chunk).await {
+        return Err(receiver_failure(stream, key.alerts()).await.unwrap_or(e));
+    }
     Ok(())
 }
 
-async fn check_duplicate(
-    stream: &mut TcpStream,
-    file: &File,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    let has_file = stream.read_u64().await?;
-    if has_file == 1 {
-        let hash = utils::hash_file(file)?;
-        stream.write_all(hash.as_ref()).await?;
-        let match_flag = stream.read_u64().await?;
-        Ok(match_flag == 0) // need transfer if hashes don't match
-    } else {
-        Ok(true) // need transfer
-    }
+/// Seals where the file's data ended, so the receiver notices chunks that never arrived.
+pub(crate) async fn write_end(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    rng: &rand::SystemRandom,
+    end: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let end = Message::FileEnd(key.seal(rng, &end.to_be_bytes())?);
+    protocol::write_message(stream, &end).await
 }
 
-async fn encrypt_and_send(
-    stream: &mut TcpStream,
-    mut file: File,
+/// Sends everything `reader` yields, then `FileEnd`. Returns where the data ended.
+async fn encrypt_and_send<R: Read + Send + 'static>(
+    stream: &mut TimeoutStream,
+    mut reader: R,
+    filename: &str,
+    offset: u64,
     size: u64,
-    key: &aead::LessSafeKey,
-) -> Result<(), Box<dyn std::error::Error>> {
+    key: &mut utils::TrafficKey,
+) -> Result<u64, Box<dyn std::error::Error>> {
     let rng = rand::SystemRandom::new();
     let mut buffer = vec![0u8; CHUNK_SIZE];
     let mut progress = utils::ProgressTracker::new();
-    let mut bytes_sent = 0u64;
+    let mut bytes_sent = offset;
 
     loop {
-        let bytes_read = file.read(&mut buffer)?;
+        // Read on the blocking pool so slow disks turn into heartbeats, not idle timeouts
+        let read_task = tokio::task::spawn_blocking(move || {
+            let result = reader.read(&mut buffer);
+            (reader, buffer, result)
+        });
+        let (returned_reader, returned_buffer, result) =
+            utils::with_heartbeat(stream, read_task).await??;
+        reader = returned_reader;
+        buffer = returned_buffer;
+
+        let bytes_read = result.map_err(|e| Reason::io(e, format_args!("reading {}", filename)))?;
         if bytes_read == 0 {
             break;
         }
 
-        let plaintext = &buffer[..bytes_read];
-        let mut in_out = plaintext.to_vec();
-
-        let nonce_bytes =
-            rand::generate::<[u8; aead::NONCE_LEN]>(&rng).map_err(|_| "RNG failure")?;
-        let nonce_slice = nonce_bytes.expose();
-        let nonce = aead::Nonce::assume_unique_for_key(nonce_slice);
-
-        key.seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| "Encryption failed")?;
-
-        // Send nonce + ciphertext
-        let mut packet = nonce_slice.to_vec();
-        packet.extend_from_slice(&in_out);
-
-        stream.write_u64(packet.len() as u64).await?;
-        stream.write_all(&packet).await?;
+        write_chunk(stream, key, &rng, &buffer[..bytes_read]).await?;
 
         bytes_sent += bytes_read as u64;
         progress.update(bytes_sent, size)?;
     }
 
-    stream.write_u64(0).await?; // Signal end of file
+    write_end(stream, key, &rng, bytes_sent).await?;
     progress.finish()?;
 
-    Ok(())
+    Ok(bytes_sent)
 }
 
 pub async fn send_file(
-    stream: &mut TcpStream,
-    file: File,
+    stream: &mut TimeoutStream,
+    mut file: File,
     filename: &str,
     size: u64,
-    key: &aead::LessSafeKey,
-    check_dup: bool,
+    key: &mut utils::TrafficKey,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let start = Instant::now();
 
-    println!("Sending file: {}", filename);
-    println!("File size: {}", format_size(size, BINARY));
+    status!("Sending file: {}", filename);
+    status!("File size: {}", format_size(size, BINARY));
 
-    send_metadata(stream, filename, size).await?;
-
-    if check_dup && !check_duplicate(stream, &file).await? {
-        println!("Recipient already has this file, skipping.");
+    // The receiver reports how much of a partially received file it already has
+    let Some(offset) = negotiate_start(stream, &file, filename, size, key).await? else {
+        status!("Recipient already has this file, skipping.");
         return Ok(());
+    };
+    if offset > size {
+        return Err("Invalid resume offset from receiver".into());
     }
+    if offset > 0 {
+        status!("Resuming at {}", format_size(offset, BINARY));
+    }
+    // Clones of a file share its position, which an earlier attempt may have moved
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| Reason::io(e, format_args!("reading {}", filename)))?;
 
-    encrypt_and_send(stream, file, size, key).await?;
+    encrypt_and_send(stream, file, filename, offset, size, key).await?;
 
     let elapsed = start.elapsed();
-    println!(
+    status!(
         "Sending took {}",
         humantime::format_duration(Duration::from_secs_f64(elapsed.as_secs_f64()))
     );
 
+    let megabits = 8.0 * ((size - offset) as f64 / 1_000_000.0);
+    status!("Speed: {:.2} Mbps", megabits / elapsed.as_secs_f64());
+
+    Ok(())
+}
+
+/// Sends data of unknown length, such as stdin. It cannot be resumed or skipped.
+pub async fn send_stream<R: Read + Send + 'static>(
+    stream: &mut TimeoutStream,
+    reader: R,
+    filename: &str,
+    key: &mut utils::TrafficKey,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    status!("Sending stream: {}", filename);
+
+    let header = FileHeader {
+        name: filename.to_string(),
+        size: protocol::UNKNOWN_SIZE,
+    };
+    protocol::write_message(stream, &Message::FileHeader(header)).await?;
+    match protocol::receive(stream, key.alerts()).await? {
+        Message::Ack(Ack::Offset(0)) => {}
+        Message::Ack(Ack::Offset(_)) => return Err("A stream cannot be resumed".into()),
+        other => return Err(protocol::unexpected("start offset", &other)),
+    }
+
+    let size = encrypt_and_send(stream, reader, filename, 0, protocol::UNKNOWN_SIZE, key).await?;
+
+    let elapsed = start.elapsed();
+    status!(
+        "Sent {} in {}",
+        format_size(size, BINARY),
+        humantime::format_duration(Duration::from_secs_f64(elapsed.as_secs_f64()))
+    );
+
     let megabits = 8.0 * (size as f64 / 1_000_000.0);
-    println!("Speed: {:.2} Mbps", megabits / elapsed.as_secs_f64());
+    status!("Speed: {:.2} Mbps", megabits / elapsed.as_secs_f64());
 
     Ok(())
 }
 
 pub async fn send_from_path(
-    stream: &mut TcpStream,
+    stream: &mut TimeoutStream,
     file_path: &Path,
     base_path: &Path,
-    key: &aead::LessSafeKey,
-    check_dup: bool,
+    key: &mut utils::TrafficKey,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let metadata = file_path.metadata()?;
-    let size = metadata.len();
+    let describe = |e| Reason::io(e, format_args!("reading {}", file_path.display()));
+    let size = file_path.metadata().map_err(describe)?.len();
+    let filename = relative_name(file_path, base_path);
+
+    let file = File::open(file_path).map_err(describe)?;
+    send_file(stream, file, &filename, size, key).await
+}
 
-    let filename = if base_path.as_os_str().is_empty() {
+/// The name a file is announced under: its path inside the sent folder, or just its name.
+pub(crate) fn relative_name(file_path: &Path, base_path: &Path) -> String {
+    if base_path.as_os_str().is_empty() {
         file_path.file_name().unwrap().to_string_lossy().to_string()
     } else {
         file_path
@@ -137,8 +236,5 @@ pub async fn send_from_path(
             .unwrap_or(file_path)
             .to_string_lossy()
             .to_string()
-    };
-
-    let file = File::open(file_path)?;
-    send_file(stream, file, &filename, size, key, check_dup).await
+    }
 }
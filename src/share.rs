@@ -0,0 +1,454 @@
+//! `flying share` serves a folder; `flying get` clients browse it and download parts of it.
+
+use crate::{
+    FileSet, mdns,
+    net::{self, TimeoutStream},
+    protocol::{self, Entry, Message, Reason, ReasonCode, Request, Response},
+    receive,
+    security::{self, SecretString},
+    utils,
+};
+use humansize::{BINARY, format_size};
+use ring::rand;
+use std::{
+    fs, io,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc, task::JoinSet};
+
+/// Maps a path requested by a client to a file or folder inside `root`, which must
+/// be canonical.
+fn resolve(root: &Path, requested: &str) -> Result<PathBuf, String> {
+    let mut path = root.to_path_buf();
+    for part in requested.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return Err(format!("{} is outside the share", requested)),
+            part => path.push(part),
+        }
+    }
+
+    // Symlinks and drive prefixes can still lead elsewhere
+    let path = path
+        .canonicalize()
+        .map_err(|e| Reason::io(e, format_args!("opening {}", requested)).text)?;
+    if !path.starts_with(root) {
+        return Err(format!("{} is outside the share", requested));
+    }
+    Ok(path)
+}
+
+fn list(root: &Path, requested: &str) -> Result<Vec<Entry>, String> {
+    let dir = resolve(root, requested)?;
+    let read_error = |e| Reason::io(e, format_args!("listing {}", requested)).text;
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if !path.canonicalize().is_ok_and(|p| p.starts_with(root)) {
+            continue;
+        }
+        let Ok(metadata) = path.metadata() else {
+            continue;
+        };
+        entries.push(Entry {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// The files a `Get` for `requested` sends, leaving out links that point outside the share.
+fn collect(root: &Path, requested: &str) -> Result<FileSet, String> {
+    let path = resolve(root, requested)?;
+    let mut file_set = FileSet::collect(&path).map_err(|e| e.to_string())?;
+    file_set
+        .files
+        .retain(|file| file.canonicalize().is_ok_and(|p| p.starts_with(root)));
+    if file_set.files.is_empty() {
+        return Err(format!("{} has no files to send", requested));
+    }
+    file_set.manifest = crate::announce(
+        &file_set.files,
+        &file_set.base_path,
+        file_set.manifest.folder.take(),
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(file_set)
+}
+
+async fn send_response(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    response: &Response,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sealed = key.seal(&rand::SystemRandom::new(), &response.encode())?;
+    protocol::write_message(stream, &Message::Response(sealed)).await
+}
+
+/// Answers a client's requests until it disconnects.
+pub(crate) async fn serve(
+    stream: &mut TimeoutStream,
+    outgoing: &mut utils::TrafficKey,
+    incoming: &mut utils::TrafficKey,
+    root: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let mut sealed = match protocol::receive(stream, incoming.alerts()).await {
+            Ok(Message::Request(sealed)) => sealed,
+            Ok(other) => return Err(protocol::unexpected("request", &other)),
+            // Clients close the connection when they are done
+            Err(e) if protocol::is_closed(e.as_ref()) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let request = Request::decode(incoming.open(&mut sealed)?)
+            .ok_or_else(|| Reason::new(ReasonCode::Protocol, "Invalid request from client"))?;
+
+        match request {
+            Request::List(path) => {
+                status!("Listing /{}", path);
+                let response = match list(root, &path) {
+                    Ok(entries) => Response::Listing(entries),
+                    Err(reason) => Response::Refused(reason),
+                };
+                send_response(stream, outgoing, &response).await?;
+            }
+            Request::Get(path) => {
+                status!("Sending /{}", path);
+                match collect(root, &path) {
+                    Ok(file_set) => {
+                        let manifest = Message::Manifest(file_set.manifest.clone());
+                        protocol::write_message(stream, &manifest).await?;
+                        crate::send_files(stream, outgoing, &file_set).await?;
+                        status!();
+                    }
+                    Err(reason) => {
+                        send_response(stream, outgoing, &Response::Refused(reason)).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One client's session, from the handshake until it disconnects.
+async fn session(
+    mut stream: TimeoutStream,
+    addr: SocketAddr,
+    password: SecretString,
+    settings: Rc<(PathBuf, net::NetworkOptions, security::SecurityOptions)>,
+    authenticated: mpsc::UnboundedSender<SocketAddr>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (root, network, security) = &*settings;
+    let (mut outgoing, mut incoming, sas) = crate::interruptible(crate::with_handshake_timeout(
+        network,
+        utils::duplex_handshake(
+            &mut stream,
+            &password,
+            security,
+            protocol::Mode::Share,
+            true,
+        ),
+    ))
+    .await?;
+    let _ = authenticated.send(addr);
+
+    let result = crate::interruptible(async {
+        security::verify_peer(&mut stream, &sas, incoming.alerts(), security).await?;
+        serve(&mut stream, &mut outgoing, &mut incoming, root).await
+    })
+    .await;
+    match &result {
+        Ok(()) => stream.shutdown().await?,
+        Err(e) => protocol::report_failure(&mut stream, outgoing.alerts(), e.as_ref()).await,
+    }
+    result
+}
+
+/// Accepts clients until Ctrl-C, serving each in its own session so one idle shell
+/// does not hold the share. Must run inside a `LocalSet`.
+pub(crate) async fn run(
+    root: PathBuf,
+    password: &SecretString,
+    network: &net::NetworkOptions,
+    security: &security::SecurityOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut password = password.clone();
+    let listener = net::create_listener(crate::DEFAULT_PORT, &network.bind)?;
+    let metadata = crate::service_metadata(mdns::Role::Share, &password, network, security);
+    let mut advertisement = mdns::Advertisement::start(
+        crate::DEFAULT_PORT,
+        &network.bind,
+        metadata,
+        network.discovery,
+    )?;
+    let mut guard = security::AccessGuard::new(security);
+
+    let settings = Rc::new((root, network.clone(), security.clone()));
+    let (authenticated_tx, mut authenticated) = mpsc::unbounded_channel();
+    let mut sessions = JoinSet::new();
+
+    status!("Listening on {}...", listener.describe());
+    status!("Waiting for clients to connect...\n");
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept(network) => match accepted {
+                Ok((_, addr)) if !guard.admit(addr) => {}
+                Ok((stream, addr)) => {
+                    let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                    status!("Connection accepted from {}", addr);
+                    let session = session(
+                        stream,
+                        addr,
+                        password.clone(),
+                        settings.clone(),
+                        authenticated_tx.clone(),
+                    );
+                    sessions.spawn_local(async move { (addr, session.await) });
+                }
+                Err(e) => eprintln!("Connection error: {}", e),
+            },
+            Some(addr) = authenticated.recv() => guard.record_success(addr),
+            Some(joined) = sessions.join_next() => match joined? {
+                (addr, Ok(())) => status!("Client {} disconnected.", addr),
+                (addr, Err(e)) => {
+                    eprintln!("Client {}: {}", addr, e);
+                    if let Some(auth_error) = e.downcast_ref()
+                        && let Err(e) = crate::record_auth_failure(
+                            &mut guard,
+                            &mut advertisement,
+                            &mut password,
+                            security,
+                            addr,
+                            auth_error,
+                        )
+                    {
+                        break Err(e);
+                    }
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                status!("\nStopping share...");
+                let reason = Reason::new(ReasonCode::Interrupted, "Interrupted by the user");
+                break Err(reason.into());
+            }
+        }
+    };
+    drop(listener);
+    drop(advertisement);
+
+    match &result {
+        // Sessions see the same Ctrl-C and tell their clients before ending
+        Err(e) if crate::is_interrupted(e.as_ref()) => {
+            while let Some(joined) = sessions.join_next().await {
+                if let (addr, Err(e)) = joined? {
+                    eprintln!("Client {}: {}", addr, e);
+                }
+            }
+        }
+        _ => sessions.shutdown().await,
+    }
+    result
+}
+
+/// The client side of a share session.
+pub(crate) struct Client<'a> {
+    pub stream: &'a mut TimeoutStream,
+    pub outgoing: &'a mut utils::TrafficKey,
+    pub incoming: &'a mut utils::TrafficKey,
+    pub output_dir: &'a Path,
+}
+
+impl Client<'_> {
+    async fn request(&mut self, request: &Request) -> Result<(), Box<dyn std::error::Error>> {
+        let sealed = self
+            .outgoing
+            .seal(&rand::SystemRandom::new(), &request.encode())?;
+        protocol::write_message(self.stream, &Message::Request(sealed)).await
+    }
+
+    async fn response(&mut self) -> Result<Response, Box<dyn std::error::Error>> {
+        let mut sealed = match protocol::receive(self.stream, self.incoming.alerts()).await? {
+            Message::Response(sealed) => sealed,
+            other => return Err(protocol::unexpected("response", &other)),
+        };
+        Response::decode(self.incoming.open(&mut sealed)?)
+            .ok_or_else(|| Reason::new(ReasonCode::Protocol, "Invalid response from share").into())
+    }
+
+    /// Returns the share's refusal as the inner error.
+    pub async fn list(
+        &mut self,
+        path: &str,
+    ) -> Result<Result<Vec<Entry>, String>, Box<dyn std::error::Error>> {
+        self.request(&Request::List(path.to_string())).await?;
+        match self.response().await? {
+            Response::Listing(entries) => Ok(Ok(entries)),
+            Response::Refused(reason) => Ok(Err(reason)),
+        }
+    }
+
+    /// Downloads a file or folder into the output directory, or returns the share's refusal.
+    pub async fn get(
+        &mut self,
+        path: &str,
+    ) -> Result<Result<(), String>, Box<dyn std::error::Error>> {
+        self.request(&Request::Get(path.to_string())).await?;
+        let mut sealed = match protocol::receive(self.stream, self.incoming.alerts()).await? {
+            Message::Manifest(manifest) => {
+                let mut resume = receive::ResumeState::default();
+                crate::receive_files(
+                    self.stream,
+                    self.incoming,
+                    manifest,
+                    self.output_dir,
+                    &mut resume,
+                )
+                .await?;
+                return Ok(Ok(()));
+            }
+            Message::Response(sealed) => sealed,
+            other => return Err(protocol::unexpected("manifest", &other)),
+        };
+        match Response::decode(self.incoming.open(&mut sealed)?) {
+            Some(Response::Refused(reason)) => Ok(Err(reason)),
+            _ => Err(Reason::new(ReasonCode::Protocol, "Invalid response from share").into()),
+        }
+    }
+}
+
+/// Resolves `path` against the shell's current folder, e.g. `../b` from `a/c` to `a/b`.
+fn join(cwd: &str, path: &str) -> String {
+    let mut parts: Vec<&str> = if path.starts_with('/') {
+        Vec::new()
+    } else {
+        cwd.split('/').filter(|part| !part.is_empty()).collect()
+    };
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+fn print_listing(entries: &[Entry]) {
+    if entries.is_empty() {
+        status!("(empty)");
+    }
+    for entry in entries {
+        if entry.is_dir {
+            status!("{:>12}  {}/", "-", entry.name);
+        } else {
+            status!("{:>12}  {}", format_size(entry.size, BINARY), entry.name);
+        }
+    }
+}
+
+/// Reads a line from stdin, sending heartbeats so the share does not time out meanwhile.
+async fn read_line(
+    stream: &mut TimeoutStream,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let task = tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        let read = io::stdin().read_line(&mut line)?;
+        Ok::<_, io::Error>((read > 0).then_some(line))
+    });
+    Ok(utils::with_heartbeat(stream, task).await???)
+}
+
+/// An interactive `ls`/`cd`/`get` prompt over the share.
+pub(crate) async fn shell(client: &mut Client<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    status!("Connected to the share. Commands: ls [path], cd <path>, get <path>..., quit");
+    let mut cwd = String::new();
+
+    loop {
+        print!("/{}> ", cwd);
+        io::stdout().flush()?;
+        let Some(line) = read_line(client.stream).await? else {
+            status!();
+            return Ok(());
+        };
+
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let args: Vec<&str> = words.collect();
+        match command {
+            "ls" => {
+                let path = join(&cwd, args.first().copied().unwrap_or(""));
+                match client.list(&path).await? {
+                    Ok(entries) => print_listing(&entries),
+                    Err(reason) => eprintln!("{}", reason),
+                }
+            }
+            "cd" => {
+                let path = join(&cwd, args.first().copied().unwrap_or("/"));
+                match client.list(&path).await? {
+                    Ok(_) => cwd = path,
+                    Err(reason) => eprintln!("{}", reason),
+                }
+            }
+            "get" if !args.is_empty() => {
+                for arg in args {
+                    if let Err(reason) = client.get(&join(&cwd, arg)).await? {
+                        eprintln!("{}", reason);
+                    }
+                }
+            }
+            "quit" | "exit" => return Ok(()),
+            _ => eprintln!("Commands: ls [path], cd <path>, get <path>..., quit"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_resolve_inside_the_share() {
+        let root = std::env::temp_dir().join(format!("flying-share-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/a.txt"), b"a").unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(resolve(&root, ""), Ok(root.clone()));
+        assert_eq!(resolve(&root, "/docs/./a.txt"), Ok(root.join("docs/a.txt")));
+        assert_eq!(resolve(&root, "docs\\a.txt"), Ok(root.join("docs/a.txt")));
+        assert!(resolve(&root, "../etc").is_err());
+        assert!(resolve(&root, "docs/../../etc").is_err());
+        assert!(resolve(&root, "missing").is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/", root.join("escape")).unwrap();
+            assert!(resolve(&root, "escape").is_err());
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn shell_paths_join_against_the_current_folder() {
+        assert_eq!(join("", "a"), "a");
+        assert_eq!(join("a/c", "../b"), "a/b");
+        assert_eq!(join("a/c", "/b"), "b");
+        assert_eq!(join("a", "../.."), "");
+    }
+}
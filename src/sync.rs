@@ -0,0 +1,297 @@
+//! `flying sync` mirrors a folder and then pushes its changes to a `flying receive --sync`.
+
+use crate::{
+    net::TimeoutStream,
+    protocol::{self, Manifest, Message, Reason, ReasonCode, SyncOp},
+    receive::{self, Existing},
+    send, utils,
+};
+use notify::{EventKind, RecursiveMode, Watcher, event::ModifyKind};
+use ring::rand;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+/// How long a burst of changes, such as an editor saving or a checkout, gets to settle.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+async fn send_op(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    op: &SyncOp,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sealed = key.seal(&rand::SystemRandom::new(), &op.encode())?;
+    protocol::write_message(stream, &Message::Sync(sealed)).await
+}
+
+/// Sends every file under `root`; the mirror skips those it already has.
+pub(crate) async fn initial_pass(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    root: &Path,
+    delete: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    crate::collect_files(root, &mut files)
+        .map_err(|e| Reason::io(e, format_args!("reading {}", root.display())))?;
+    let folder = root
+        .file_name()
+        .ok_or("Invalid folder name")?
+        .to_string_lossy()
+        .to_string();
+    let manifest = crate::announce(&files, root, Some(folder))?;
+    protocol::write_message(stream, &Message::Manifest(manifest)).await?;
+
+    status!("Syncing {} file(s)...", files.len());
+    for (i, file) in files.iter().enumerate() {
+        status!("\n===========================================");
+        status!("File {} of {}", i + 1, files.len());
+        status!("===========================================");
+        send::send_from_path(stream, file, root, key).await?;
+    }
+    if delete {
+        send_op(stream, key, &SyncOp::Prune).await?;
+    }
+    Ok(())
+}
+
+/// Pushes the files behind `changed`, and with `delete` the removal of those that are gone.
+/// `moved_in` holds folders whose whole contents are new.
+async fn push_changes(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    root: &Path,
+    changed: &BTreeSet<PathBuf>,
+    moved_in: &BTreeSet<PathBuf>,
+    delete: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = BTreeSet::new();
+    for dir in moved_in {
+        let mut inside = Vec::new();
+        if crate::collect_files(dir, &mut inside).is_ok() {
+            files.extend(inside);
+        }
+    }
+
+    for path in changed {
+        if path == root || !path.starts_with(root) {
+            continue;
+        }
+        if path.is_file() {
+            files.insert(path.clone());
+        } else if delete && !path.exists() {
+            let name = send::relative_name(path, root);
+            send_op(stream, key, &SyncOp::Remove(name.replace('\\', "/"))).await?;
+            status!("Removed: {}", name);
+        }
+    }
+
+    for path in files {
+        // Temporary files are often gone again by now
+        let Ok(file) = fs::File::open(&path) else {
+            continue;
+        };
+        let name = send::relative_name(&path, root);
+        let size = file
+            .metadata()
+            .map_err(|e| Reason::io(e, format_args!("reading {}", name)))?
+            .len();
+        let manifest = Manifest::new(None, std::iter::once((name.as_str(), size)));
+        status!();
+        protocol::write_message(stream, &Message::Manifest(manifest)).await?;
+        send::send_file(stream, file, &name, size, key).await?;
+    }
+    Ok(())
+}
+
+/// Pushes changes under `root` as they happen, until the user presses Ctrl-C.
+pub(crate) async fn watch(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    root: &Path,
+    delete: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (events, mut pending) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = events.send(event);
+    })?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    status!(
+        "\nWatching {} for changes, press Ctrl-C to stop...",
+        root.display()
+    );
+
+    loop {
+        // Heartbeats keep the mirror from timing out while nothing changes
+        let first = tokio::select! {
+            event = utils::with_heartbeat(stream, pending.recv()) => event?,
+            _ = tokio::signal::ctrl_c() => return send_op(stream, key, &SyncOp::Stop).await,
+        };
+        let first = first.ok_or("Stopped watching for changes")?;
+        tokio::time::sleep(SETTLE_TIME).await;
+
+        let mut changed = BTreeSet::new();
+        let mut moved_in = BTreeSet::new();
+        let rest = std::iter::from_fn(|| pending.try_recv().ok());
+        for event in std::iter::once(first).chain(rest) {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    eprintln!("Watch error: {}", e);
+                    continue;
+                }
+            };
+            match event.kind {
+                EventKind::Access(_) => {}
+                EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) => {
+                    for path in event.paths {
+                        if path.is_dir() {
+                            moved_in.insert(path);
+                        } else {
+                            changed.insert(path);
+                        }
+                    }
+                }
+                _ => changed.extend(event.paths),
+            }
+        }
+
+        crate::interruptible(push_changes(stream, key, root, &changed, &moved_in, delete)).await?;
+    }
+}
+
+fn remove(folder: &Path, relative: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let path = receive::local_path(folder, relative)
+        .ok_or_else(|| Reason::new(ReasonCode::Protocol, "Invalid path from source"))?;
+    let result = if path.is_dir() {
+        fs::remove_dir_all(&path)
+    } else {
+        fs::remove_file(&path)
+    };
+    match result {
+        Ok(()) => status!("Removed: {}", relative),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Reason::io(e, format_args!("removing {}", relative)).into()),
+    }
+    Ok(())
+}
+
+/// Deletes the files under `folder` that are not in `keep`, and the folders left empty.
+fn prune(folder: &Path, keep: &HashSet<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    crate::collect_files(folder, &mut files)
+        .map_err(|e| Reason::io(e, format_args!("reading {}", folder.display())))?;
+    for file in files.iter().filter(|file| !keep.contains(*file)) {
+        let name = send::relative_name(file, folder);
+        fs::remove_file(file).map_err(|e| Reason::io(e, format_args!("removing {}", name)))?;
+        status!("Removed: {}", name);
+    }
+    remove_empty_dirs(folder, folder)?;
+    Ok(())
+}
+
+/// Removes the folders below `dir` that contain no files. Returns whether `dir` is empty.
+fn remove_empty_dirs(dir: &Path, folder: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let reading = |e| Reason::io(e, format_args!("reading {}", dir.display()));
+    let mut empty = true;
+    for entry in fs::read_dir(dir).map_err(reading)? {
+        let entry = entry.map_err(reading)?;
+        // Symlinks are left alone, even to folders, so nothing outside `folder` is touched
+        let is_dir = entry.file_type().map_err(reading)?.is_dir();
+        let path = entry.path();
+        if !is_dir || !remove_empty_dirs(&path, folder)? {
+            empty = false;
+            continue;
+        }
+        let name = send::relative_name(&path, folder);
+        fs::remove_dir(&path).map_err(|e| Reason::io(e, format_args!("removing {}", name)))?;
+        status!("Removed: {}", name);
+    }
+    Ok(empty)
+}
+
+/// Applies a syncing folder's files and changes under `output_dir` until the source stops.
+pub(crate) async fn mirror(
+    stream: &mut TimeoutStream,
+    key: &mut utils::TrafficKey,
+    output_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = match protocol::receive(stream, key.alerts()).await? {
+        Message::Manifest(manifest) => manifest,
+        other => return Err(protocol::unexpected("manifest", &other)),
+    };
+    let folder = manifest
+        .folder
+        .as_deref()
+        .and_then(|name| receive::local_folder(output_dir, name))
+        .ok_or_else(|| Reason::new(ReasonCode::Protocol, "Invalid folder from source"))?;
+    fs::create_dir_all(&folder)
+        .map_err(|e| Reason::io(e, format_args!("creating folder {}", folder.display())))?;
+
+    status!(
+        "Syncing {} file(s) into {}...\n",
+        manifest.files,
+        folder.display()
+    );
+    let mut resume = receive::ResumeState::default();
+    let mut received = HashSet::new();
+    for _ in 0..manifest.files {
+        let name =
+            receive::receive_file(stream, &folder, key, Existing::Replace, &mut resume).await?;
+        received.insert(folder.join(name));
+        status!();
+    }
+    status!("Up to date, waiting for changes...");
+
+    loop {
+        match protocol::receive(stream, key.alerts()).await? {
+            Message::Manifest(batch) => {
+                for _ in 0..batch.files {
+                    status!();
+                    receive::receive_file(stream, &folder, key, Existing::Replace, &mut resume)
+                        .await?;
+                }
+            }
+            Message::Sync(mut sealed) => {
+                let op = SyncOp::decode(key.open(&mut sealed)?).ok_or_else(|| {
+                    Reason::new(ReasonCode::Protocol, "Invalid change from source")
+                })?;
+                match op {
+                    SyncOp::Remove(path) => remove(&folder, &path)?,
+                    SyncOp::Prune => prune(&folder, &received)?,
+                    SyncOp::Stop => return Ok(()),
+                }
+            }
+            other => return Err(protocol::unexpected("change", &other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pruning_removes_emptied_folders() {
+        let folder = std::env::temp_dir().join(format!("flying-prune-{}", std::process::id()));
+        fs::create_dir_all(folder.join("gone/deeper")).unwrap();
+        fs::create_dir_all(folder.join("kept")).unwrap();
+        for file in ["gone/deeper/a", "kept/b", "kept/c", "d"] {
+            fs::write(folder.join(file), b"x").unwrap();
+        }
+
+        let keep = HashSet::from([folder.join("kept/b")]);
+        prune(&folder, &keep).unwrap();
+
+        assert!(!folder.join("gone").exists());
+        assert!(!folder.join("kept/c").exists());
+        assert!(!folder.join("d").exists());
+        assert!(folder.join("kept/b").exists());
+        assert!(folder.exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
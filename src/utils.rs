@@ -1,16 +1,35 @@
-use ring::{digest, hkdf, hmac};
+use crate::{
+    identity::{PUBLIC_KEY_LEN, TrustedDevice, TrustedSession, fingerprint},
+    net::TimeoutStream,
+    protocol::{self, Capabilities, Hello, Manifest, Message, Mode, Reason},
+    security::{AuthError, SAS_LEN, Sas, SecretString, SecurityOptions, SessionKey},
+};
+use humansize::{BINARY, format_size};
+use ring::{agreement, digest, hkdf, hmac, rand, signature};
 use spake2::{Ed25519Group, Identity, Password, Spake2};
 use std::{
     fs,
+    future::Future,
     io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
 };
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use zeroize::Zeroizing;
 
 const SPAKE2_MSG_SIZE: usize = 33;
 const HMAC_TAG_SIZE: usize = 32;
+const X25519_KEY_SIZE: usize = 32;
+const ED25519_SIGNATURE_SIZE: usize = 64;
+const MAX_DEVICE_NAME: usize = 256;
+
+/// Ciphers this build can use for file data.
+pub const CIPHERS: &[Cipher] = &[Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305];
+
+/// Messages sealed under one key before ratcheting, far below the 2^32 random-nonce bound.
+const REKEY_AFTER_MESSAGES: u64 = 1 << 24;
+/// How far ahead of the receiver the sender's epoch may be.
+const MAX_EPOCH_SKIP: u32 = 16;
+const EPOCH_LEN: usize = 4;
 
 struct MyKeyType(usize);
 
@@ -20,8 +39,532 @@ impl hkdf::KeyType for MyKeyType {
     }
 }
 
-pub fn generate_password() -> String {
-    petname::petname(3, "-").unwrap_or_else(|| "flying-transfer-secret".to_string())
+/// AEAD used for file data, agreed on during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    /// Faster than AES on CPUs without AES instructions, e.g. many phones and ARM boards.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        CIPHERS.iter().copied().find(|c| c.id() == id)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    fn tag_len(self) -> usize {
+        self.algorithm().tag_len()
+    }
+
+    fn algorithm(self) -> &'static ring::aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &ring::aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &ring::aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// Ciphers to offer, best first: `forced` alone, or AES-256-GCM first only when
+    /// this CPU accelerates it.
+    pub fn preferences(forced: Option<Cipher>) -> Vec<Cipher> {
+        match forced {
+            Some(cipher) => vec![cipher],
+            None if has_aes_acceleration() => vec![Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305],
+            None => vec![Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+        }
+    }
+
+    /// Picks the cipher both offers allow. When the two sides prefer different
+    /// ciphers, one of them lacks AES instructions, so ChaCha20-Poly1305 wins.
+    fn negotiate(ours: &[Cipher], theirs: &[Cipher]) -> Option<Cipher> {
+        let our_best = ours.iter().find(|c| theirs.contains(c))?;
+        let their_best = theirs.iter().find(|c| ours.contains(c))?;
+        if our_best == their_best {
+            Some(*our_best)
+        } else {
+            Some(Cipher::ChaCha20Poly1305)
+        }
+    }
+}
+
+impl std::fmt::Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CIPHERS
+            .iter()
+            .copied()
+            .find(|c| c.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown cipher '{}' (expected aes-256-gcm or chacha20-poly1305)",
+                    s
+                )
+            })
+    }
+}
+
+pub fn has_aes_acceleration() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Seals and opens file data, ratcheting to a fresh key every epoch so that no
+/// single key protects enough data to approach the random-nonce limits of AES-GCM.
+///
+/// Frames are `epoch || nonce || ciphertext`, with the epoch also used as associated
+/// data. Each epoch's secret is derived from the previous one and then wiped, so
+/// the sender alone decides when to rekey and the receiver follows.
+pub struct TrafficKey {
+    cipher: Cipher,
+    secret: SessionKey,
+    key: ring::aead::LessSafeKey,
+    epoch: u32,
+    sealed_bytes: u64,
+    sealed_messages: u64,
+    rekey_after: u64,
+    alerts: AlertKey,
+    /// The optional features both sides agreed on for this session.
+    capabilities: Capabilities,
+}
+
+impl TrafficKey {
+    fn new(
+        cipher: Cipher,
+        secret: SessionKey,
+        rekey_after: u64,
+        alerts: AlertKey,
+        capabilities: Capabilities,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = Self::aead_key(cipher, &secret)?;
+        Ok(TrafficKey {
+            cipher,
+            secret,
+            key,
+            epoch: 0,
+            sealed_bytes: 0,
+            sealed_messages: 0,
+            rekey_after,
+            alerts,
+            capabilities,
+        })
+    }
+
+    pub fn alerts(&self) -> &AlertKey {
+        &self.alerts
+    }
+
+    /// Whether both sides agreed to continue interrupted transfers.
+    pub fn resumes(&self) -> bool {
+        self.capabilities.contains(Capabilities::RESUME)
+    }
+
+    fn aead_key(
+        cipher: Cipher,
+        secret: &SessionKey,
+    ) -> Result<ring::aead::LessSafeKey, Box<dyn std::error::Error>> {
+        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret.as_ref());
+        let mut key_bytes = Zeroizing::new([0u8; 32]);
+        expand_key(&prk, &[b"traffic-key"], key_bytes.as_mut())?;
+        let unbound_key = ring::aead::UnboundKey::new(cipher.algorithm(), key_bytes.as_ref())
+            .map_err(|_| "Failed to create encryption key")?;
+        Ok(ring::aead::LessSafeKey::new(unbound_key))
+    }
+
+    /// The secret of the epoch after the one `secret` belongs to.
+    fn next_secret(secret: &SessionKey) -> Result<SessionKey, Box<dyn std::error::Error>> {
+        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret.as_ref());
+        let mut next = SessionKey::default();
+        expand_key(&prk, &[b"rekey"], next.as_mut())?;
+        Ok(next)
+    }
+
+    /// Moves to `epoch`, whose secret is `secret`.
+    fn advance(&mut self, epoch: u32, secret: SessionKey, key: ring::aead::LessSafeKey) {
+        self.key = key;
+        self.secret = secret;
+        self.epoch = epoch;
+        self.sealed_bytes = 0;
+        self.sealed_messages = 0;
+    }
+
+    fn ratchet(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let next = Self::next_secret(&self.secret)?;
+        let key = Self::aead_key(self.cipher, &next)?;
+        let epoch = self.epoch.checked_add(1).ok_or("Key epochs exhausted")?;
+        self.advance(epoch, next, key);
+        Ok(())
+    }
+
+    /// Encrypts `plaintext` into a frame, moving to the next epoch first if this key is used up.
+    pub fn seal(
+        &mut self,
+        rng: &rand::SystemRandom,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if self.sealed_bytes >= self.rekey_after || self.sealed_messages >= REKEY_AFTER_MESSAGES {
+            self.ratchet()?;
+        }
+
+        let epoch = self.epoch.to_be_bytes();
+        let nonce_bytes = rand::generate::<[u8; ring::aead::NONCE_LEN]>(rng)
+            .map_err(|_| "RNG failure")?
+            .expose();
+        let nonce = ring::aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        let header_len = EPOCH_LEN + ring::aead::NONCE_LEN;
+        let mut frame = Vec::with_capacity(header_len + plaintext.len() + self.cipher.tag_len());
+        frame.extend_from_slice(&epoch);
+        frame.extend_from_slice(&nonce_bytes);
+        frame.extend_from_slice(plaintext);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                nonce,
+                ring::aead::Aad::from(epoch),
+                &mut frame[header_len..],
+            )
+            .map_err(|_| "Encryption failed")?;
+        frame.extend_from_slice(tag.as_ref());
+
+        self.sealed_bytes += plaintext.len() as u64;
+        self.sealed_messages += 1;
+        Ok(frame)
+    }
+
+    /// Decrypts a frame in place, following the sender to a later epoch if needed.
+    pub fn open<'a>(
+        &mut self,
+        frame: &'a mut [u8],
+    ) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if frame.len() < EPOCH_LEN + ring::aead::NONCE_LEN {
+            return Err("Invalid packet: too short".into());
+        }
+        let (header, ciphertext) = frame.split_at_mut(EPOCH_LEN + ring::aead::NONCE_LEN);
+        let (epoch, nonce_bytes) = header.split_at(EPOCH_LEN);
+        let epoch: [u8; EPOCH_LEN] = epoch.try_into()?;
+
+        let frame_epoch = u32::from_be_bytes(epoch);
+        if frame_epoch < self.epoch || frame_epoch - self.epoch > MAX_EPOCH_SKIP {
+            return Err(format!(
+                "Invalid key epoch {} (expected {})",
+                frame_epoch, self.epoch
+            )
+            .into());
+        }
+        // A later epoch is only adopted once a frame from it authenticates, so a
+        // forged epoch cannot move this side ahead of the sender
+        let mut next = None;
+        if frame_epoch > self.epoch {
+            let mut secret = Self::next_secret(&self.secret)?;
+            for _ in self.epoch + 1..frame_epoch {
+                secret = Self::next_secret(&secret)?;
+            }
+            let key = Self::aead_key(self.cipher, &secret)?;
+            next = Some((secret, key));
+        }
+
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce_bytes)
+            .map_err(|_| "Invalid nonce")?;
+        let key = next.as_ref().map_or(&self.key, |(_, key)| key);
+        let plaintext = key
+            .open_in_place(nonce, ring::aead::Aad::from(epoch), ciphertext)
+            .map_err(|_| "Decryption failed")?;
+        if let Some((secret, key)) = next {
+            self.advance(frame_epoch, secret, key);
+        }
+        Ok(plaintext)
+    }
+}
+
+/// Seals the `Reason` in `Error` and `Cancel` messages, so only the peer can make
+/// this side stop with a failure.
+///
+/// Both directions use it and it never ratchets, as a session carries at most a
+/// couple of reports. The associated data is whether the sending side accepted
+/// the connection, which differs between the two sides even when their modes match,
+/// so a report cannot be reflected back to its author.
+pub struct AlertKey {
+    key: ring::aead::LessSafeKey,
+    mode: Mode,
+    is_listener: bool,
+}
+
+impl AlertKey {
+    pub(crate) fn new(
+        secret: &SessionKey,
+        mode: Mode,
+        is_listener: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, secret.as_ref());
+        let mut key_bytes = Zeroizing::new([0u8; 32]);
+        expand_key(&prk, &[b"alert-key"], key_bytes.as_mut())?;
+        // Fixed so that pairing, which negotiates no cipher, can use it too
+        let unbound_key =
+            ring::aead::UnboundKey::new(&ring::aead::CHACHA20_POLY1305, key_bytes.as_ref())
+                .map_err(|_| "Failed to create alert key")?;
+        Ok(AlertKey {
+            key: ring::aead::LessSafeKey::new(unbound_key),
+            mode,
+            is_listener,
+        })
+    }
+
+    /// The mode of the side whose reports this key opens.
+    pub fn peer(&self) -> Mode {
+        self.mode.peer()
+    }
+
+    /// `nonce || ciphertext || tag`.
+    pub(crate) fn seal(&self, reason: &Reason) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let nonce_bytes = rand::generate::<[u8; ring::aead::NONCE_LEN]>(&rand::SystemRandom::new())
+            .map_err(|_| "RNG failure")?
+            .expose();
+        let nonce = ring::aead::Nonce::assume_unique_for_key(nonce_bytes);
+
+        let mut sealed = nonce_bytes.to_vec();
+        sealed.extend_from_slice(&reason.encode());
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                nonce,
+                ring::aead::Aad::from([self.is_listener as u8]),
+                &mut sealed[ring::aead::NONCE_LEN..],
+            )
+            .map_err(|_| "Encryption failed")?;
+        sealed.extend_from_slice(tag.as_ref());
+        Ok(sealed)
+    }
+
+    pub(crate) fn open(&self, mut sealed: Vec<u8>) -> Option<Reason> {
+        if sealed.len() < ring::aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at_mut(ring::aead::NONCE_LEN);
+        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce_bytes).ok()?;
+        let plaintext = self
+            .key
+            .open_in_place(
+                nonce,
+                ring::aead::Aad::from([!self.is_listener as u8]),
+                ciphertext,
+            )
+            .ok()?;
+        Reason::decode(plaintext)
+    }
+}
+
+/// How generated passwords look. Every format starts with a numeric session tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordFormat {
+    /// `427-purple-sailing-fox`, with the given number of words.
+    Words(u8),
+    /// `427-8392057164`, with the given number of secret digits, easy to type on a keypad.
+    Numeric(u8),
+    /// `427-purple-fox`, a short channel-and-two-words code in the style of magic-wormhole.
+    Wormhole,
+}
+
+impl Default for PasswordFormat {
+    fn default() -> Self {
+        PasswordFormat::Words(3)
+    }
+}
+
+impl PasswordFormat {
+    /// Sets the number of words or digits. Wormhole codes always have two words.
+    pub fn with_length(self, length: u8) -> Self {
+        match self {
+            PasswordFormat::Words(_) => PasswordFormat::Words(length),
+            PasswordFormat::Numeric(_) => PasswordFormat::Numeric(length),
+            PasswordFormat::Wormhole => PasswordFormat::Wormhole,
+        }
+    }
+}
+
+impl std::str::FromStr for PasswordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "words" => Ok(PasswordFormat::Words(3)),
+            "numeric" => Ok(PasswordFormat::Numeric(10)),
+            "wormhole" => Ok(PasswordFormat::Wormhole),
+            _ => Err(format!(
+                "Unknown password format '{}' (expected words, numeric or wormhole)",
+                s
+            )),
+        }
+    }
+}
+
+fn random_below(rng: &rand::SystemRandom, bound: u32) -> Result<u32, Box<dyn std::error::Error>> {
+    // Reject the top of the range so every value is equally likely
+    let zone = u32::MAX - u32::MAX % bound;
+    loop {
+        let value = u32::from_be_bytes(
+            rand::generate::<[u8; 4]>(rng)
+                .map_err(|_| "System random number generator failed")?
+                .expose(),
+        );
+        if value < zone {
+            return Ok(value % bound);
+        }
+    }
+}
+
+pub fn generate_password(
+    format: PasswordFormat,
+) -> Result<SecretString, Box<dyn std::error::Error>> {
+    let rng = rand::SystemRandom::new();
+    let tag = random_below(&rng, 999)? + 1;
+
+    let secret = match format {
+        PasswordFormat::Words(0) | PasswordFormat::Numeric(0) => {
+            return Err("Password length must be at least 1".into());
+        }
+        PasswordFormat::Words(n) => petname::petname(n, "-"),
+        PasswordFormat::Wormhole => petname::petname(2, "-"),
+        PasswordFormat::Numeric(n) => (0..n)
+            .map(|_| random_below(&rng, 10).map(|d| char::from(b'0' + d as u8)))
+            .collect::<Result<String, _>>()
+            .ok(),
+    };
+    let secret = Zeroizing::new(secret.ok_or("Failed to generate password")?);
+
+    // Sized up front so the buffer holding the secret is never reallocated
+    let tag = tag.to_string();
+    let mut password = String::with_capacity(tag.len() + 1 + secret.len());
+    password.push_str(&tag);
+    password.push('-');
+    password.push_str(&secret);
+    Ok(SecretString::new(password))
+}
+
+/// Passwords below this estimated strength get a warning.
+const WEAK_PASSWORD_BITS: f64 = 40.0;
+/// The same for generated codes, whose strength is known exactly. The default formats
+/// stay above it.
+const WEAK_CODE_BITS: f64 = 30.0;
+
+/// The strength of a password generated in `format`, without its session tag.
+fn format_bits(format: PasswordFormat) -> f64 {
+    match format {
+        PasswordFormat::Words(n) => (petname::Petnames::default().cardinality(n) as f64).log2(),
+        PasswordFormat::Numeric(n) => f64::from(n) * 10f64.log2(),
+        PasswordFormat::Wormhole => format_bits(PasswordFormat::Words(2)),
+    }
+}
+
+/// Warns about typed-in passwords that are easy to guess.
+///
+/// Codes in a generated format are rated by how many codes that format allows. For
+/// anything else the estimate is crude: it assumes every character is random within
+/// the character classes used, so it only catches passwords that are short or use a
+/// tiny alphabet.
+pub fn password_warning(password: &str) -> Option<String> {
+    let (bits, floor) = match session_tag(password) {
+        Some(tag) => {
+            let format = generated_format(&password[tag.len() + 1..])?;
+            (format_bits(format), WEAK_CODE_BITS)
+        }
+        None => (character_bits(password), WEAK_PASSWORD_BITS),
+    };
+    (bits < floor).then(|| {
+        format!(
+            "Warning: weak password (about {:.0} bits). Compare the verification code, or use a longer generated password.",
+            bits
+        )
+    })
+}
+
+fn character_bits(secret: &str) -> f64 {
+    let mut alphabet = 0u32;
+    if secret.chars().any(|c| c.is_ascii_lowercase()) {
+        alphabet += 26;
+    }
+    if secret.chars().any(|c| c.is_ascii_uppercase()) {
+        alphabet += 26;
+    }
+    if secret.chars().any(|c| c.is_ascii_digit()) {
+        alphabet += 10;
+    }
+    if secret.chars().any(|c| !c.is_ascii_alphanumeric()) {
+        alphabet += 33;
+    }
+
+    secret.chars().count() as f64 * f64::from(alphabet.max(1)).log2()
+}
+
+/// Returns the format `secret`, a password without its tag, could have been generated in.
+fn generated_format(secret: &str) -> Option<PasswordFormat> {
+    if !secret.is_empty() && secret.bytes().all(|b| b.is_ascii_digit()) {
+        return u8::try_from(secret.len()).ok().map(PasswordFormat::Numeric);
+    }
+
+    // Petnames are adverbs, then an adjective, then a noun
+    let words: Vec<&str> = secret.split('-').collect();
+    let petnames = petname::Petnames::default();
+    let (noun, rest) = words.split_last()?;
+    let in_order = petnames.nouns.contains(noun)
+        && match rest.split_last() {
+            Some((adjective, adverbs)) => {
+                petnames.adjectives.contains(adjective)
+                    && adverbs
+                        .iter()
+                        .all(|adverb| petnames.adverbs.contains(adverb))
+            }
+            None => true,
+        };
+    in_order
+        .then(|| u8::try_from(words.len()).ok().map(PasswordFormat::Words))
+        .flatten()
+}
+
+/// Returns the public session tag of a password like `427-purple-sailing-fox`.
+///
+/// Listeners advertise the tag over mDNS so receivers can find the right peer. Only
+/// passwords in a generated format have one: there the tag is a random number chosen
+/// independently of the secret words, while in a self-chosen password like
+/// `2024-mydog` it would give part of the secret away.
+pub fn session_tag(password: &str) -> Option<&str> {
+    let (tag, rest) = password.split_once('-')?;
+    let is_tag = !tag.is_empty()
+        && tag.len() <= 3
+        && tag.bytes().all(|b| b.is_ascii_digit())
+        && generated_format(rest).is_some();
+    is_tag.then_some(tag)
 }
 
 pub fn hash_file(file: &fs::File) -> io::Result<digest::Digest> {
@@ -45,103 +588,246 @@ pub fn hash_file(file: &fs::File) -> io::Result<digest::Digest> {
     Ok(context.finish())
 }
 
+/// Drives `task` to completion while sending heartbeats to keep the peer's idle timer alive.
+pub async fn with_heartbeat<F: Future>(
+    stream: &mut TimeoutStream,
+    task: F,
+) -> Result<F::Output, Box<dyn std::error::Error>> {
+    let mut interval = tokio::time::interval(stream.heartbeat_interval());
+    interval.tick().await;
+    tokio::pin!(task);
+
+    loop {
+        tokio::select! {
+            output = &mut task => return Ok(output),
+            _ = interval.tick() => protocol::write_message(stream, &Message::Heartbeat).await?,
+        }
+    }
+}
+
+/// Hashes a file on the blocking pool, sending heartbeats meanwhile.
+pub async fn hash_file_with_heartbeat(
+    stream: &mut TimeoutStream,
+    file: &fs::File,
+) -> Result<digest::Digest, Box<dyn std::error::Error>> {
+    let file = file.try_clone()?;
+    let task = tokio::task::spawn_blocking(move || hash_file(&file));
+    Ok(with_heartbeat(stream, task).await???)
+}
+
+/// How often the size of a stream of unknown length is shown.
+const STREAM_PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+
 pub struct ProgressTracker {
     last_percent: u8,
+    /// Bytes so far of a stream whose total is unknown, shown instead of a percentage.
+    streamed: Option<u64>,
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProgressTracker {
     pub fn new() -> Self {
-        Self { last_percent: 0 }
+        Self {
+            last_percent: 0,
+            streamed: None,
+        }
     }
 
     pub fn update(&mut self, bytes_processed: u64, total_bytes: u64) -> io::Result<()> {
+        if total_bytes == protocol::UNKNOWN_SIZE {
+            let step = |bytes| bytes / STREAM_PROGRESS_STEP;
+            if self
+                .streamed
+                .is_none_or(|last| step(bytes_processed) > step(last))
+            {
+                let mut out = status_output();
+                write!(out, "\rProgress: {}", format_size(bytes_processed, BINARY))?;
+                out.flush()?;
+            }
+            self.streamed = Some(bytes_processed);
+            return Ok(());
+        }
+
         let percent_done = ((bytes_processed as f64 / total_bytes as f64) * 100.0) as u8;
         if percent_done > self.last_percent {
-            print!("\rProgress: {}%", percent_done);
-            io::stdout().flush()?;
+            let mut out = status_output();
+            write!(out, "\rProgress: {}%", percent_done)?;
+            out.flush()?;
             self.last_percent = percent_done;
         }
         Ok(())
     }
 
     pub fn finish(&self) -> io::Result<()> {
-        println!("\rProgress: 100%");
+        match self.streamed {
+            Some(bytes) => status!("\rProgress: {}", format_size(bytes, BINARY)),
+            None => status!("\rProgress: 100%"),
+        }
         Ok(())
     }
 }
 
-pub async fn version_handshake(
-    stream: &mut TcpStream,
-    version: u64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (mut read_half, mut write_half) = stream.split();
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
 
-    let (write_result, read_result) =
-        tokio::join!(write_half.write_u64(version), read_half.read_u64());
+/// Moves status output to stderr for the rest of the process, leaving stdout to
+/// received data.
+pub fn reserve_stdout() {
+    STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+}
 
-    write_result?;
-    let peer_version = read_result?;
+pub fn stdout_is_data() -> bool {
+    STDOUT_IS_DATA.load(Ordering::Relaxed)
+}
 
-    if peer_version != version {
-        println!(
-            "Warning: Version mismatch (local: {}, peer: {})",
-            version, peer_version
-        );
+/// Where status output goes: stdout, or stderr once stdout carries data.
+pub fn status_output() -> Box<dyn Write> {
+    if stdout_is_data() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
     }
+}
 
-    Ok(())
+/// What both hellos agreed on.
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    pub capabilities: Capabilities,
+    /// `None` when neither side offered a cipher, as in pairing.
+    pub cipher: Option<Cipher>,
+    /// Both hellos, the sender's first, bound into authentication so that
+    /// tampering with either one makes key confirmation fail.
+    transcript: Vec<u8>,
 }
 
-pub async fn mode_handshake(
-    stream: &mut TcpStream,
+/// Swaps hellos and settles the protocol version, mode, capabilities and cipher.
+pub async fn hello_handshake(
+    stream: &mut TimeoutStream,
+    mode: Mode,
+    ciphers: &[Cipher],
     is_receiver: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    const MODE_SEND: u64 = 1;
-    const MODE_RECEIVE: u64 = 0;
-
-    let (our_mode, expected_peer_mode) = if is_receiver {
-        (MODE_RECEIVE, MODE_SEND)
-    } else {
-        (MODE_SEND, MODE_RECEIVE)
+) -> Result<Negotiated, Box<dyn std::error::Error>> {
+    let ours = Hello {
+        min_version: crate::MIN_VERSION,
+        max_version: crate::VERSION,
+        mode,
+        capabilities: Capabilities::SUPPORTED,
+        ciphers: ciphers.to_vec(),
     };
+    let our_bytes = ours.encode();
 
-    let (mut read_half, mut write_half) = stream.split();
-
-    let (write_result, read_result) =
-        tokio::join!(write_half.write_u64(our_mode), read_half.read_u64());
+    // Both sides write first; a hello always fits in the socket buffer
+    protocol::write_message(stream, &Message::Hello(ours.clone())).await?;
 
-    write_result?;
-    let peer_mode = read_result?;
+    // Read the raw frame so the transcript covers the peer's hello exactly as sent
+    let (tag, peer_bytes) = protocol::read_frame(stream, protocol::MAX_HELLO_LEN).await?;
+    if tag != protocol::HELLO_TAG {
+        return Err("Protocol error: expected hello".into());
+    }
+    let theirs = Hello::decode(&peer_bytes).ok_or("Invalid hello from peer")?;
 
-    if peer_mode != expected_peer_mode {
+    let version = ours.max_version.min(theirs.max_version);
+    if version < ours.min_version.max(theirs.min_version) {
+        let update = if theirs.max_version < ours.min_version {
+            "the peer"
+        } else {
+            "this device"
+        };
         return Err(format!(
-            "Mode mismatch: both sides in {} mode",
-            if peer_mode == MODE_SEND {
-                "send"
-            } else {
-                "receive"
-            }
+            "No common protocol version: this device speaks {} to {}, the peer {} to {}. Update flying on {}",
+            ours.min_version, ours.max_version, theirs.min_version, theirs.max_version, update
         )
         .into());
     }
 
+    if theirs.mode != mode.peer() {
+        return Err(if theirs.mode == mode {
+            format!("Mode mismatch: both sides in {} mode", mode.as_str())
+        } else {
+            format!("Mode mismatch: peer is in {} mode", theirs.mode.as_str())
+        }
+        .into());
+    }
+
+    let cipher = if ours.ciphers.is_empty() && theirs.ciphers.is_empty() {
+        None
+    } else {
+        let cipher = Cipher::negotiate(&ours.ciphers, &theirs.ciphers).ok_or_else(|| {
+            let names: Vec<&str> = theirs.ciphers.iter().map(|c| c.as_str()).collect();
+            format!("No cipher in common, peer offers: {}", names.join(", "))
+        })?;
+        Some(cipher)
+    };
+
+    let (sender_hello, receiver_hello) = if is_receiver {
+        (&peer_bytes, &our_bytes)
+    } else {
+        (&our_bytes, &peer_bytes)
+    };
+    let transcript = [
+        &(sender_hello.len() as u64).to_be_bytes()[..],
+        sender_hello,
+        &(receiver_hello.len() as u64).to_be_bytes(),
+        receiver_hello,
+    ]
+    .concat();
+
+    Ok(Negotiated {
+        capabilities: ours.capabilities.intersection(theirs.capabilities),
+        cipher,
+        transcript,
+    })
+}
+
+/// Agrees on password or paired-device authentication before either is attempted.
+async fn auth_handshake(
+    stream: &mut TimeoutStream,
+    trusted: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    stream.write_u64(u64::from(trusted)).await?;
+    let peer_trusted = stream.read_u64().await? == 1;
+
+    match (trusted, peer_trusted) {
+        (true, false) => Err("Peer expects a password, not a paired device".into()),
+        (false, true) => Err("Peer only accepts paired devices".into()),
+        _ => Ok(()),
+    }
+}
+
+fn expand_key(
+    prk: &hkdf::Prk,
+    info: &[&[u8]],
+    out: &mut [u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    prk.expand(info, MyKeyType(out.len()))
+        .map_err(|_| "HKDF expand failed")?
+        .fill(out)
+        .map_err(|_| "HKDF key derivation failed")?;
     Ok(())
 }
 
+/// Runs SPAKE2 and confirms the key over `context`, handshake data both sides must agree on.
 pub async fn pake_handshake(
-    stream: &mut TcpStream,
-    password: &str,
+    stream: &mut TimeoutStream,
+    password: &SecretString,
     is_receiver: bool,
-) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    context: &[u8],
+) -> Result<(SessionKey, Sas), Box<dyn std::error::Error>> {
+    // spake2 keeps its own copy of the password, which it does not wipe
+    let password = Password::new(password.expose());
     let (state, outbound_msg) = if is_receiver {
         Spake2::<Ed25519Group>::start_b(
-            &Password::new(password),
+            &password,
             &Identity::new(b"sender"),
             &Identity::new(b"receiver"),
         )
     } else {
         Spake2::<Ed25519Group>::start_a(
-            &Password::new(password),
+            &password,
             &Identity::new(b"sender"),
             &Identity::new(b"receiver"),
         )
@@ -152,34 +838,27 @@ pub async fn pake_handshake(
     let mut inbound_msg = vec![0u8; SPAKE2_MSG_SIZE];
     stream.read_exact(&mut inbound_msg).await?;
 
-    let shared_secret = state
-        .finish(&inbound_msg)
-        .map_err(|_| "PAKE failed: incorrect password or protocol error")?;
-
-    let shared_secret: [u8; 32] = shared_secret
-        .try_into()
-        .map_err(|_| "Invalid shared secret length")?;
+    let shared_secret = Zeroizing::new(
+        state
+            .finish(&inbound_msg)
+            .map_err(|_| "PAKE failed: incorrect password or protocol error")?,
+    );
+    if shared_secret.len() != 32 {
+        return Err("Invalid shared secret length".into());
+    }
 
     // Derive encryption key using HKDF
     let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, b"flying-v5");
     let prk = salt.extract(&shared_secret);
 
-    let aead_info: &[&[u8]] = &[b"aead-key"];
-    let mut aead_key = [0u8; 32];
-    prk.expand(aead_info, MyKeyType(32))
-        .map_err(|_| "HKDF expand failed")?
-        .fill(&mut aead_key)
-        .map_err(|_| "HKDF key derivation failed")?;
+    let mut aead_key = SessionKey::default();
+    expand_key(&prk, &[b"aead-key"], aead_key.as_mut())?;
 
     // Key confirmation using HMAC
-    let hmac_info: &[&[u8]] = &[b"hmac-key"];
-    let mut hmac_key_bytes = [0u8; 32];
-    prk.expand(hmac_info, MyKeyType(32))
-        .map_err(|_| "HKDF expand failed")?
-        .fill(&mut hmac_key_bytes)
-        .map_err(|_| "HKDF key derivation failed")?;
+    let mut hmac_key_bytes = Zeroizing::new([0u8; 32]);
+    expand_key(&prk, &[b"hmac-key"], hmac_key_bytes.as_mut())?;
 
-    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &hmac_key_bytes);
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, hmac_key_bytes.as_ref());
 
     let (our_role, peer_role): (&[u8], &[u8]) = if is_receiver {
         (b"receiver", b"sender")
@@ -187,86 +866,506 @@ pub async fn pake_handshake(
         (b"sender", b"receiver")
     };
 
-    let our_tag = hmac::sign(&hmac_key, our_role);
-    stream.write_all(our_tag.as_ref()).await?;
-
+    let our_tag = hmac::sign(&hmac_key, &[our_role, context].concat());
     let mut peer_tag = vec![0u8; HMAC_TAG_SIZE];
-    stream.read_exact(&mut peer_tag).await?;
 
-    hmac::verify(&hmac_key, peer_role, &peer_tag)
-        .map_err(|_| "Key confirmation failed: password mismatch")?;
+    // The listener only answers a correct tag, so a connector cannot take its tag, test
+    // guesses offline and hang up without the attempt counting as a failure
+    if stream.is_listener() {
+        stream.read_exact(&mut peer_tag).await?;
+        hmac::verify(&hmac_key, &[peer_role, context].concat(), &peer_tag)
+            .map_err(|_| AuthError::PasswordMismatch)?;
+        stream.write_all(our_tag.as_ref()).await?;
+    } else {
+        stream.write_all(our_tag.as_ref()).await?;
+        stream.read_exact(&mut peer_tag).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                Box::new(AuthError::PasswordMismatch) as Box<dyn std::error::Error>
+            } else {
+                e.into()
+            }
+        })?;
+        hmac::verify(&hmac_key, &[peer_role, context].concat(), &peer_tag)
+            .map_err(|_| AuthError::PasswordMismatch)?;
+    }
 
-    Ok(aead_key)
+    // Bind the verification string to both PAKE messages, sender's first
+    let (sender_msg, receiver_msg) = if is_receiver {
+        (&inbound_msg[..], &outbound_msg[..])
+    } else {
+        (&outbound_msg[..], &inbound_msg[..])
+    };
+    let mut sas_bytes = [0u8; SAS_LEN];
+    expand_key(&prk, &[b"sas", sender_msg, receiver_msg], &mut sas_bytes)?;
+
+    Ok((aead_key, Sas::new(sas_bytes)))
 }
 
-pub async fn send_handshake(
-    stream: &mut TcpStream,
-    version: u64,
-    password: &str,
-    num_files: u64,
-    is_folder: bool,
-    folder_name: Option<&str>,
-) -> Result<ring::aead::LessSafeKey, Box<dyn std::error::Error>> {
-    version_handshake(stream, version).await?;
-    mode_handshake(stream, false).await?;
-    let key_bytes = pake_handshake(stream, password, false).await?;
+/// Mutually authenticates two paired devices and agrees on a fresh session key.
+///
+/// Each side sends its identity key and an ephemeral X25519 key, then signs the
+/// transcript. The session key comes from the ephemeral exchange, so recorded
+/// sessions stay safe even if an identity key leaks later. `context` is signed along
+/// with the transcript.
+pub async fn trusted_handshake(
+    stream: &mut TimeoutStream,
+    session: &TrustedSession,
+    is_receiver: bool,
+    context: &[u8],
+) -> Result<(SessionKey, Sas), Box<dyn std::error::Error>> {
+    let rng = rand::SystemRandom::new();
+    let ephemeral = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)
+        .map_err(|_| "Failed to generate ephemeral key")?;
+    let ephemeral_public = ephemeral
+        .compute_public_key()
+        .map_err(|_| "Failed to compute ephemeral key")?;
+
+    let hello = [session.identity.public_key(), ephemeral_public.as_ref()].concat();
+    stream.write_all(&hello).await?;
+
+    let mut peer_hello = [0u8; PUBLIC_KEY_LEN + X25519_KEY_SIZE];
+    stream.read_exact(&mut peer_hello).await?;
+    let (peer_identity, peer_ephemeral) = peer_hello.split_at(PUBLIC_KEY_LEN);
+
+    let peer = session
+        .find_peer(peer_identity)
+        .ok_or_else(|| AuthError::UnknownDevice(fingerprint(peer_identity)))?;
+
+    let (sender_hello, receiver_hello) = if is_receiver {
+        (&peer_hello[..], &hello[..])
+    } else {
+        (&hello[..], &peer_hello[..])
+    };
+    let transcript = digest::digest(
+        &digest::SHA256,
+        &[
+            b"flying-trusted".as_slice(),
+            sender_hello,
+            receiver_hello,
+            context,
+        ]
+        .concat(),
+    );
+
+    let (our_role, peer_role): (&[u8], &[u8]) = if is_receiver {
+        (b"receiver", b"sender")
+    } else {
+        (b"sender", b"receiver")
+    };
+
+    let our_signature = session
+        .identity
+        .sign(&[our_role, transcript.as_ref()].concat());
+    stream.write_all(our_signature.as_ref()).await?;
+
+    let mut peer_signature = [0u8; ED25519_SIGNATURE_SIZE];
+    stream.read_exact(&mut peer_signature).await?;
 
-    stream.write_u64(num_files).await?;
-    stream.write_u64(u64::from(is_folder)).await?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, &peer.public_key)
+        .verify(&[peer_role, transcript.as_ref()].concat(), &peer_signature)
+        .map_err(|_| AuthError::BadSignature(peer.name.clone()))?;
 
-    if let Some(name) = folder_name {
-        stream.write_u64(name.len() as u64).await?;
-        stream.write_all(name.as_bytes()).await?;
+    let shared_secret = agreement::agree_ephemeral(
+        ephemeral,
+        &agreement::UnparsedPublicKey::new(&agreement::X25519, peer_ephemeral),
+        |secret| Zeroizing::new(secret.to_vec()),
+    )
+    .map_err(|_| "Key agreement failed")?;
+
+    let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, transcript.as_ref());
+    let prk = salt.extract(&shared_secret);
+
+    let mut aead_key = SessionKey::default();
+    expand_key(&prk, &[b"aead-key"], aead_key.as_mut())?;
+    let mut sas_bytes = [0u8; SAS_LEN];
+    expand_key(&prk, &[b"sas"], &mut sas_bytes)?;
+
+    status!(
+        "Authenticated paired device {} ({})",
+        peer.name,
+        peer.fingerprint()
+    );
+    Ok((aead_key, Sas::new(sas_bytes)))
+}
+
+async fn authenticate(
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    security: &SecurityOptions,
+    is_receiver: bool,
+    context: &[u8],
+) -> Result<(SessionKey, Sas), Box<dyn std::error::Error>> {
+    auth_handshake(stream, security.trusted.is_some()).await?;
+    match security.trusted {
+        Some(ref session) => trusted_handshake(stream, session, is_receiver, context).await,
+        None => pake_handshake(stream, password, is_receiver, context).await,
+    }
+}
+
+/// Runs the password exchange for `flying pair`, before identity keys are swapped.
+pub async fn pair_handshake(
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    is_listener: bool,
+) -> Result<(SessionKey, Sas), Box<dyn std::error::Error>> {
+    let negotiated = hello_handshake(stream, Mode::Pair, &[], !is_listener).await?;
+    pake_handshake(stream, password, !is_listener, &negotiated.transcript).await
+}
+
+/// Swaps identity keys and device names, each tagged with the PAKE key so they
+/// cannot be replaced in transit.
+pub async fn exchange_identities(
+    stream: &mut TimeoutStream,
+    key: &[u8; 32],
+    identity: &crate::identity::Identity,
+    device_name: &str,
+    is_listener: bool,
+) -> Result<TrustedDevice, Box<dyn std::error::Error>> {
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let (our_role, peer_role): (&[u8], &[u8]) = if is_listener {
+        (b"pair-listener", b"pair-connector")
+    } else {
+        (b"pair-connector", b"pair-listener")
+    };
+
+    let name = device_name.as_bytes();
+    let name = &name[..name.len().min(MAX_DEVICE_NAME)];
+    let message = [identity.public_key(), name].concat();
+    let tag = hmac::sign(&hmac_key, &[our_role, &message].concat());
+
+    stream.write_u64(message.len() as u64).await?;
+    stream.write_all(&message).await?;
+    stream.write_all(tag.as_ref()).await?;
+
+    let len = stream.read_u64().await? as usize;
+    if !(PUBLIC_KEY_LEN..=PUBLIC_KEY_LEN + MAX_DEVICE_NAME).contains(&len) {
+        return Err("Invalid identity from peer".into());
     }
+    let mut peer_message = vec![0u8; len];
+    stream.read_exact(&mut peer_message).await?;
+    let mut peer_tag = [0u8; HMAC_TAG_SIZE];
+    stream.read_exact(&mut peer_tag).await?;
 
-    let unbound_key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, &key_bytes)
-        .map_err(|_| "Failed to create encryption key")?;
-    Ok(ring::aead::LessSafeKey::new(unbound_key))
+    hmac::verify(&hmac_key, &[peer_role, &peer_message].concat(), &peer_tag)
+        .map_err(|_| "Identity exchange failed: key was tampered with")?;
+
+    let (public_key, name) = peer_message.split_at(PUBLIC_KEY_LEN);
+    // The store is line based, so keep names on one line
+    let name = String::from_utf8_lossy(name).replace(['\n', '\r'], " ");
+    Ok(TrustedDevice {
+        name: if name.trim().is_empty() {
+            fingerprint(public_key)
+        } else {
+            name.trim().to_string()
+        },
+        public_key: public_key.to_vec(),
+    })
+}
+
+pub async fn send_handshake(
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    security: &SecurityOptions,
+    manifest: Manifest,
+) -> Result<(TrafficKey, Sas), Box<dyn std::error::Error>> {
+    let negotiated = hello_handshake(
+        stream,
+        Mode::Send,
+        &Cipher::preferences(security.cipher),
+        false,
+    )
+    .await?;
+    let cipher = negotiated.cipher.ok_or("Peer offered no cipher")?;
+    let (key_bytes, sas) =
+        authenticate(stream, password, security, false, &negotiated.transcript).await?;
+    status!("Cipher: {}", cipher);
+
+    protocol::write_message(stream, &Message::Manifest(manifest)).await?;
+
+    let alerts = AlertKey::new(&key_bytes, Mode::Send, stream.is_listener())?;
+    let key = TrafficKey::new(
+        cipher,
+        key_bytes,
+        security.rekey_after,
+        alerts,
+        negotiated.capabilities,
+    )?;
+    Ok((key, sas))
 }
 
 pub async fn receive_handshake(
-    stream: &mut TcpStream,
-    version: u64,
-    password: &str,
-) -> Result<(ring::aead::LessSafeKey, u64, bool, Option<String>), Box<dyn std::error::Error>> {
-    version_handshake(stream, version).await?;
-    mode_handshake(stream, true).await?;
-    let key_bytes = pake_handshake(stream, password, true).await?;
-
-    let num_files = stream.read_u64().await?;
-    let is_folder = stream.read_u64().await? == 1;
-
-    let folder_name = if is_folder {
-        let len = stream.read_u64().await? as usize;
-        let mut bytes = vec![0; len];
-        stream.read_exact(&mut bytes).await?;
-        Some(String::from_utf8_lossy(&bytes).to_string())
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    security: &SecurityOptions,
+) -> Result<(TrafficKey, Sas, Manifest), Box<dyn std::error::Error>> {
+    let negotiated = hello_handshake(
+        stream,
+        Mode::Receive,
+        &Cipher::preferences(security.cipher),
+        true,
+    )
+    .await?;
+    let cipher = negotiated.cipher.ok_or("Peer offered no cipher")?;
+    let (key_bytes, sas) =
+        authenticate(stream, password, security, true, &negotiated.transcript).await?;
+    status!("Cipher: {}", cipher);
+
+    let alerts = AlertKey::new(&key_bytes, Mode::Receive, stream.is_listener())?;
+    let key = TrafficKey::new(
+        cipher,
+        key_bytes,
+        security.rekey_after,
+        alerts,
+        negotiated.capabilities,
+    )?;
+    let manifest = match protocol::receive(stream, key.alerts()).await? {
+        Message::Manifest(manifest) => manifest,
+        other => return Err(protocol::unexpected("manifest", &other)),
+    };
+
+    Ok((key, sas, manifest))
+}
+
+/// Runs the handshake for sessions where both sides send, as in `flying exchange`
+/// and `flying share`.
+///
+/// Returns separate keys for the data this side sends and the data it receives, so
+/// each direction ratchets on its own.
+pub async fn duplex_handshake(
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    security: &SecurityOptions,
+    mode: Mode,
+    is_listener: bool,
+) -> Result<(TrafficKey, TrafficKey, Sas), Box<dyn std::error::Error>> {
+    let negotiated = hello_handshake(
+        stream,
+        mode,
+        &Cipher::preferences(security.cipher),
+        !is_listener,
+    )
+    .await?;
+    let cipher = negotiated.cipher.ok_or("Peer offered no cipher")?;
+    let (key_bytes, sas) = authenticate(
+        stream,
+        password,
+        security,
+        !is_listener,
+        &negotiated.transcript,
+    )
+    .await?;
+    status!("Cipher: {}", cipher);
+
+    let prk = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, key_bytes.as_ref());
+    let mut from_listener = SessionKey::default();
+    let mut from_connector = SessionKey::default();
+    expand_key(&prk, &[b"listener-to-connector"], from_listener.as_mut())?;
+    expand_key(&prk, &[b"connector-to-listener"], from_connector.as_mut())?;
+    let (outgoing, incoming) = if is_listener {
+        (from_listener, from_connector)
     } else {
-        None
+        (from_connector, from_listener)
     };
 
-    let unbound_key = ring::aead::UnboundKey::new(&ring::aead::AES_256_GCM, &key_bytes)
-        .map_err(|_| "Failed to create decryption key")?;
-    let key = ring::aead::LessSafeKey::new(unbound_key);
+    let outgoing = TrafficKey::new(
+        cipher,
+        outgoing,
+        security.rekey_after,
+        AlertKey::new(&key_bytes, mode, is_listener)?,
+        negotiated.capabilities,
+    )?;
+    let incoming = TrafficKey::new(
+        cipher,
+        incoming,
+        security.rekey_after,
+        AlertKey::new(&key_bytes, mode, is_listener)?,
+        negotiated.capabilities,
+    )?;
+    Ok((outgoing, incoming, sas))
+}
 
-    Ok((key, num_files, is_folder, folder_name))
+/// Runs the handshake for `flying exchange` and swaps manifests.
+pub async fn exchange_handshake(
+    stream: &mut TimeoutStream,
+    password: &SecretString,
+    security: &SecurityOptions,
+    manifest: Manifest,
+    is_listener: bool,
+) -> Result<(TrafficKey, TrafficKey, Sas, Manifest), Box<dyn std::error::Error>> {
+    let (outgoing, incoming, sas) =
+        duplex_handshake(stream, password, security, Mode::Exchange, is_listener).await?;
+
+    // Both sides write first; a manifest always fits in the socket buffer
+    protocol::write_message(stream, &Message::Manifest(manifest)).await?;
+    let peer_manifest = match protocol::receive(stream, incoming.alerts()).await? {
+        Message::Manifest(manifest) => manifest,
+        other => return Err(protocol::unexpected("manifest", &other)),
+    };
+
+    Ok((outgoing, incoming, sas, peer_manifest))
 }
 
-pub fn create_listener(port: u16) -> Result<tokio::net::TcpListener, Box<dyn std::error::Error>> {
-    use socket2::{Domain, Protocol, Socket, Type};
-    use std::net::SocketAddr;
+/// A sender and receiver sharing one secret, the sender rekeying after every frame.
+#[cfg(test)]
+pub(crate) fn key_pair() -> (TrafficKey, TrafficKey) {
+    let secret = SessionKey::new([7; 32]);
+    let key = |mode| {
+        let alerts = AlertKey::new(&secret, mode, mode == Mode::Send).unwrap();
+        TrafficKey::new(
+            Cipher::ChaCha20Poly1305,
+            secret.clone(),
+            1,
+            alerts,
+            Capabilities::SUPPORTED,
+        )
+        .unwrap()
+    };
+    (key(Mode::Send), key(Mode::Receive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ReasonCode;
+
+    const BOTH: [Cipher; 2] = [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305];
+
+    #[test]
+    fn both_sides_negotiate_the_same_cipher() {
+        let offers: [&[Cipher]; 5] = [
+            &BOTH,
+            &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+            &[Cipher::Aes256Gcm],
+            &[Cipher::ChaCha20Poly1305],
+            &[],
+        ];
+        for ours in offers {
+            for theirs in offers {
+                assert_eq!(
+                    Cipher::negotiate(ours, theirs),
+                    Cipher::negotiate(theirs, ours),
+                    "{:?} / {:?}",
+                    ours,
+                    theirs
+                );
+            }
+        }
+
+        assert_eq!(Cipher::negotiate(&BOTH, &BOTH), Some(Cipher::Aes256Gcm));
+        assert_eq!(
+            Cipher::negotiate(&BOTH, &[Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm]),
+            Some(Cipher::ChaCha20Poly1305)
+        );
+        assert_eq!(
+            Cipher::negotiate(&[Cipher::Aes256Gcm], &[Cipher::ChaCha20Poly1305]),
+            None
+        );
+    }
 
-    let addr = format!("[::]:{}", port).parse::<SocketAddr>()?;
+    #[test]
+    fn receiver_follows_the_sender_across_epochs() {
+        let rng = rand::SystemRandom::new();
+        let (mut sender, mut receiver) = key_pair();
 
-    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-    socket.set_only_v6(false)?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&addr.into())?;
-    socket.listen(128)?;
+        for i in 0..=MAX_EPOCH_SKIP {
+            let mut frame = sender.seal(&rng, &[i as u8]).unwrap();
+            assert_eq!(receiver.open(&mut frame).unwrap(), [i as u8]);
+        }
 
-    let std_listener: std::net::TcpListener = socket.into();
-    std_listener.set_nonblocking(true)?;
-    let listener = tokio::net::TcpListener::from_std(std_listener)?;
+        // Frames may be skipped, as long as the receiver can catch up
+        for _ in 1..MAX_EPOCH_SKIP {
+            sender.seal(&rng, b"lost").unwrap();
+        }
+        let mut frame = sender.seal(&rng, b"caught up").unwrap();
+        assert_eq!(receiver.open(&mut frame).unwrap(), b"caught up");
+    }
 
-    Ok(listener)
+    #[test]
+    fn old_and_distant_epochs_are_rejected() {
+        let rng = rand::SystemRandom::new();
+        let (mut sender, mut receiver) = key_pair();
+
+        let old = sender.seal(&rng, b"old").unwrap();
+        let mut current = sender.seal(&rng, b"current").unwrap();
+        assert_eq!(receiver.open(&mut current).unwrap(), b"current");
+        assert!(receiver.open(&mut old.clone()).is_err());
+
+        for _ in 0..MAX_EPOCH_SKIP {
+            sender.seal(&rng, b"lost").unwrap();
+        }
+        let mut distant = sender.seal(&rng, b"distant").unwrap();
+        assert!(receiver.open(&mut distant).is_err());
+    }
+
+    #[test]
+    fn forged_epochs_do_not_advance_the_receiver() {
+        let rng = rand::SystemRandom::new();
+        let (mut sender, mut receiver) = key_pair();
+
+        let mut forged = sender.seal(&rng, b"first").unwrap();
+        forged[..EPOCH_LEN].copy_from_slice(&MAX_EPOCH_SKIP.to_be_bytes());
+        assert!(receiver.open(&mut forged).is_err());
+
+        let mut frame = sender.seal(&rng, b"second").unwrap();
+        assert_eq!(receiver.open(&mut frame).unwrap(), b"second");
+    }
+
+    #[test]
+    fn alerts_cannot_be_reflected() {
+        let secret = SessionKey::new([9; 32]);
+        let reason = Reason::new(ReasonCode::Interrupted, "stop");
+        // Both sides of an exchange share a mode, so only the role tells them apart
+        let listener = AlertKey::new(&secret, Mode::Exchange, true).unwrap();
+        let connector = AlertKey::new(&secret, Mode::Exchange, false).unwrap();
+
+        let sealed = listener.seal(&reason).unwrap();
+        assert_eq!(connector.open(sealed.clone()), Some(reason.clone()));
+        assert_eq!(listener.open(sealed), None);
+
+        let sealed = connector.seal(&reason).unwrap();
+        assert_eq!(listener.open(sealed.clone()), Some(reason));
+        assert_eq!(connector.open(sealed), None);
+    }
+
+    #[test]
+    fn generated_passwords_are_recognised_by_format() {
+        for (format, recognised) in [
+            (PasswordFormat::Words(3), PasswordFormat::Words(3)),
+            (PasswordFormat::Words(1), PasswordFormat::Words(1)),
+            (PasswordFormat::Numeric(10), PasswordFormat::Numeric(10)),
+            (PasswordFormat::Wormhole, PasswordFormat::Words(2)),
+        ] {
+            let password = generate_password(format).unwrap();
+            let tag = session_tag(password.expose()).unwrap();
+            let secret = &password.expose()[tag.len() + 1..];
+            assert_eq!(generated_format(secret), Some(recognised), "{}", secret);
+        }
+        assert!(generate_password(PasswordFormat::Words(0)).is_err());
+        assert!(generate_password(PasswordFormat::Numeric(0)).is_err());
+
+        // Out-of-order or made-up words are not a generated format
+        assert_eq!(generated_format("fox-purple"), None);
+        assert_eq!(generated_format("mydog"), None);
+        assert_eq!(generated_format(""), None);
+    }
+
+    #[test]
+    fn weak_passwords_get_a_warning() {
+        for format in [PasswordFormat::default(), PasswordFormat::Numeric(10)] {
+            let password = generate_password(format).unwrap();
+            assert_eq!(password_warning(password.expose()), None, "{:?}", format);
+        }
+        for format in [PasswordFormat::Numeric(4), PasswordFormat::Wormhole] {
+            let password = generate_password(format).unwrap();
+            assert!(
+                password_warning(password.expose()).is_some(),
+                "{:?}",
+                format
+            );
+        }
+
+        assert!(password_warning("hunter2").is_some());
+        assert!(password_warning("secret").is_some());
+        assert_eq!(password_warning("Tr0ub4dor&3-horse-battery"), None);
+    }
 }